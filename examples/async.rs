@@ -2,7 +2,6 @@ use rascam::*;
 use std::time::Duration;
 use tokio::fs::File;
 use tokio::prelude::*;
-use tokio::time::delay_for;
 use tracing::{error, info};
 
 #[tokio::main]
@@ -32,7 +31,9 @@ async fn simple_async(info: &CameraInfo) -> Result<(), Box<dyn std::error::Error
     let mut camera = SimpleCamera::new(info.clone())?;
     camera.activate()?;
 
-    delay_for(Duration::from_millis(2000)).await;
+    camera
+        .wait_until_settled_async(Duration::from_secs(5))
+        .await?;
 
     let b = camera.take_one_async().await?;
     let mut file = File::create("image.jpg").await?;
@@ -0,0 +1,59 @@
+use rascam::*;
+use std::time;
+use tracing::info;
+
+// Make sure to run with --release
+//
+// Benchmarks the colour conversions on a full resolution (2592x1944) frame. This does not
+// need a camera.
+
+const WIDTH: u32 = 2592;
+const HEIGHT: u32 = 1944;
+
+fn main() {
+    // Set up logging to stdout
+    tracing_subscriber::fmt::init();
+
+    let space = ColorSpace::default();
+    let rgb = Frame::packed(
+        (0..WIDTH * HEIGHT * 3).map(|i| (i % 251) as u8).collect(),
+        PixelFormat::Rgb24,
+        WIDTH,
+        HEIGHT,
+    );
+
+    for &format in [PixelFormat::I420, PixelFormat::Nv12, PixelFormat::Yuyv].iter() {
+        let yuv = convert(&rgb, format, space).unwrap();
+
+        bench(&format!("RGB24 -> {:?}", format), 10, || {
+            convert(&rgb, format, space).unwrap();
+        });
+        bench(&format!("{:?} -> RGB24", format), 10, || {
+            convert(&yuv, PixelFormat::Rgb24, space).unwrap();
+        });
+        bench(&format!("{:?} -> RGBA", format), 10, || {
+            convert(&yuv, PixelFormat::Rgba, space).unwrap();
+        });
+    }
+}
+
+// Prints the average time taken to run the function
+fn bench<F>(name: &str, n: u32, mut f: F)
+where
+    F: FnMut(),
+{
+    let start = time::Instant::now();
+    for _ in 0..n {
+        f();
+    }
+    let duration = time::Instant::now().duration_since(start);
+    let runtime_secs =
+        duration.as_secs() as f64 + (duration.subsec_nanos() as f64 / 1_000_000_000.0);
+
+    info!(
+        "{}: {:.2} ms/frame, {:.2} frames/sec",
+        name,
+        runtime_secs * 1000.0 / n as f64,
+        n as f64 / runtime_secs
+    );
+}
@@ -33,11 +33,14 @@ fn serious(info: &CameraInfo) {
 
     let settings = CameraSettings {
         encoding: MMAL_ENCODING_RGB24,
-        width: 96, // 96px will not require padding
-        height: 96,
+        width: 100, // the camera pads this to 128x80
+        height: 75,
         iso: ISO_AUTO,
         zero_copy: true,
         use_encoder: false,
+        awb_gains: None,
+        pool: PoolSettings::default(),
+        still_resizer: None,
     };
 
     camera.set_camera_format(&settings).unwrap();
@@ -63,14 +66,20 @@ fn serious(info: &CameraInfo) {
 
     let buffer = receiver.recv().unwrap().unwrap();
 
+    // Strip the padding added by the camera so that the image can be read by other tools
+    let frame = Frame::new(
+        buffer.get_bytes().to_vec(),
+        PixelFormat::Rgb24,
+        settings.width,
+        settings.height,
+    )
+    .to_packed();
+
     File::create("image.rgb")
         .unwrap()
-        .write_all(&buffer.get_bytes())
+        .write_all(frame.data())
         .unwrap();
 
     info!("Raw rgb bytes written to image.rgb");
-    info!("Try: convert -size 96x96 -depth 8 -colorspace RGB rgb:image.rgb image.png");
-    // If imagemagick gives something like:
-    //   convert-im6.q16: unexpected end-of-file `image.rgb': No such file or directory @ error/rgb.c/ReadRGBImage/239.
-    // There is probably padding in the image. Check the width.
+    info!("Try: convert -size 100x75 -depth 8 -colorspace RGB rgb:image.rgb image.png");
 }
@@ -1,7 +1,7 @@
 use rascam::*;
 use std::fs::File;
 use std::io::Write;
-use std::{thread, time};
+use std::time::Duration;
 use tracing::{error, info};
 
 fn main() {
@@ -23,8 +23,7 @@ fn simple_sync(info: &CameraInfo) {
     let mut camera = SimpleCamera::new(info.clone()).unwrap();
     camera.activate().unwrap();
 
-    let sleep_duration = time::Duration::from_millis(2000);
-    thread::sleep(sleep_duration);
+    camera.wait_until_settled(Duration::from_secs(5)).unwrap();
 
     let b = camera.take_one().unwrap();
     File::create("image.jpg").unwrap().write_all(&b).unwrap();
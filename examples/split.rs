@@ -0,0 +1,67 @@
+use rascam::*;
+use std::fs::File;
+use std::io::Write;
+use tracing::{error, info};
+
+// Records H.264 to video.h264 while counting frames scaled down to 320x240 from the same camera.
+
+fn main() {
+    // Set up logging to stdout
+    tracing_subscriber::fmt::init();
+
+    let info = info().unwrap();
+    if info.cameras.len() < 1 {
+        error!("Found 0 cameras. Exiting");
+        // note that this doesn't run destructors
+        ::std::process::exit(1);
+    }
+    info!("{}", info);
+
+    split(&info.cameras[0]);
+}
+
+fn split(info: &CameraInfo) {
+    let mut camera = SimpleCamera::new(info.clone()).unwrap();
+    camera.activate().unwrap();
+
+    let settings = VideoSettings {
+        width: 1280,
+        height: 720,
+        frame_rate: 30,
+    };
+    let outputs = [
+        SplitterOutput::Encoder(EncoderSettings {
+            intra_period: Some(30),
+            ..EncoderSettings::default()
+        }),
+        SplitterOutput::Resizer(ResizerSettings {
+            format: PixelFormat::I420,
+            width: 320,
+            height: 240,
+            ..ResizerSettings::default()
+        }),
+    ];
+    let mut streams = camera.split(settings, &outputs).unwrap();
+    let frames = streams.pop().unwrap().into_frames().unwrap();
+    let mut video = streams.pop().unwrap().into_video().unwrap();
+
+    let mut file = File::create("video.h264").unwrap();
+    let mut bytes = 0;
+    for frame in frames.take(150) {
+        frame.unwrap();
+
+        // Write whatever the encoder has produced since the last frame
+        while let Some(buffer) = video.try_next_buffer().unwrap() {
+            if buffer.is_side_info() {
+                continue;
+            }
+            bytes += buffer.data().len();
+            file.write_all(buffer.data()).unwrap();
+        }
+    }
+
+    info!(
+        "150 scaled frames received, {} bytes of H.264 written to video.h264",
+        bytes
+    );
+}
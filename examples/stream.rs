@@ -0,0 +1,57 @@
+use rascam::*;
+use std::fs::File;
+use std::io::Write;
+use std::time;
+use tracing::{error, info};
+
+fn main() {
+    // Set up logging to stdout
+    tracing_subscriber::fmt::init();
+
+    let info = info().unwrap();
+    if info.cameras.len() < 1 {
+        error!("Found 0 cameras. Exiting");
+        // note that this doesn't run destructors
+        ::std::process::exit(1);
+    }
+    info!("{}", info);
+
+    stream(&info.cameras[0]);
+}
+
+fn stream(info: &CameraInfo) {
+    let mut camera = SimpleCamera::new(info.clone()).unwrap();
+    camera.activate().unwrap();
+
+    let settings = StreamSettings {
+        format: PixelFormat::I420,
+        width: 640,
+        height: 480,
+        frame_rate: 30,
+        buffers: 3,
+    };
+    let stream = camera.stream(settings).unwrap();
+
+    let frames = 150;
+    let start = time::Instant::now();
+    let mut last = None;
+    for frame in stream.take(frames) {
+        last = Some(frame.unwrap());
+    }
+    let duration = time::Instant::now().duration_since(start);
+    info!(
+        "{} frames in {:?}, {:.2} frames/sec",
+        frames,
+        duration,
+        frames as f64 / duration.as_secs_f64()
+    );
+
+    let frame = last.unwrap().to_packed();
+    File::create("frame.yuv")
+        .unwrap()
+        .write_all(frame.data())
+        .unwrap();
+
+    info!("Raw I420 bytes written to frame.yuv");
+    info!("Try: convert -size 640x480 -depth 8 -sampling-factor 4:2:0 yuv:frame.yuv frame.png");
+}
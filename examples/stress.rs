@@ -1,5 +1,5 @@
 use rascam::*;
-use std::{thread, time};
+use std::time;
 use tracing::{error, info};
 
 // Make sure to run with --release
@@ -46,8 +46,7 @@ fn bench_jpegs_per_sec(n: i32) {
 
     let mut b = Box::new(camera);
 
-    let sleep_duration = time::Duration::from_millis(2000);
-    thread::sleep(sleep_duration);
+    b.wait_until_settled(time::Duration::from_secs(5)).unwrap();
 
     for _ in 0..n {
         let images = 30;
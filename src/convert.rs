@@ -0,0 +1,531 @@
+use crate::frame::{Frame, PixelFormat};
+
+/// Fixed point scale used by the conversions. Coefficients are stored multiplied by `1 << 16`.
+const SHIFT: i32 = 16;
+const HALF: i32 = 1 << (SHIFT - 1);
+
+/// The matrix used to convert between RGB and YUV.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMatrix {
+    /// ITU-R BT.601, used by JPEG and standard definition video.
+    Bt601,
+    /// ITU-R BT.709, used by high definition video.
+    Bt709,
+}
+
+impl ColorMatrix {
+    /// Returns the red and blue luma weights (Kr, Kb).
+    fn weights(self) -> (f64, f64) {
+        match self {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// The range of values used by the Y, U and V samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorRange {
+    /// Samples use the full 0-255 range. Also known as JPEG or PC range.
+    Full,
+    /// Y uses 16-235 and U/V use 16-240. Also known as video or TV range.
+    Limited,
+}
+
+/// Describes how YUV samples map to RGB.
+///
+/// The default is BT.601 full range, which is what the camera produces for stills.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColorSpace {
+    pub matrix: ColorMatrix,
+    pub range: ColorRange,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        ColorSpace {
+            matrix: ColorMatrix::Bt601,
+            range: ColorRange::Full,
+        }
+    }
+}
+
+/// Converts a frame between a YUV format (I420, NV12 or YUYV) and an RGB format (RGB24, BGR24
+/// or RGBA).
+///
+/// The returned frame contains no padding. Chroma is averaged over each block of pixels when
+/// converting to YUV and shared between the pixels of each block when converting from YUV.
+///
+/// Returns `None` if the conversion between the two formats is not supported.
+///
+/// ```
+/// use rascam::{convert, ColorSpace, Frame, PixelFormat};
+///
+/// // A 2x2 grey I420 image
+/// let yuv = Frame::packed(vec![128, 128, 128, 128, 128, 128], PixelFormat::I420, 2, 2);
+/// let rgb = convert(&yuv, PixelFormat::Rgb24, ColorSpace::default()).unwrap();
+/// assert_eq!(rgb.data(), &[128; 12][..]);
+/// ```
+pub fn convert(frame: &Frame, format: PixelFormat, space: ColorSpace) -> Option<Frame> {
+    if is_yuv(frame.format()) && !is_yuv(format) {
+        Some(YuvToRgb::new(space).convert(frame, format))
+    } else if !is_yuv(frame.format()) && is_yuv(format) {
+        Some(RgbToYuv::new(space).convert(frame, format))
+    } else {
+        None
+    }
+}
+
+fn is_yuv(format: PixelFormat) -> bool {
+    match format {
+        PixelFormat::I420 | PixelFormat::Nv12 | PixelFormat::Yuyv => true,
+        PixelFormat::Rgb24 | PixelFormat::Bgr24 | PixelFormat::Rgba => false,
+    }
+}
+
+/// The pixel size and the positions of the red and blue samples of an RGB format. Green is
+/// always in the middle.
+trait RgbLayout {
+    const SIZE: usize;
+    const R: usize;
+    const B: usize;
+}
+
+struct Rgb24;
+struct Bgr24;
+struct Rgba;
+
+impl RgbLayout for Rgb24 {
+    const SIZE: usize = 3;
+    const R: usize = 0;
+    const B: usize = 2;
+}
+
+impl RgbLayout for Bgr24 {
+    const SIZE: usize = 3;
+    const R: usize = 2;
+    const B: usize = 0;
+}
+
+impl RgbLayout for Rgba {
+    const SIZE: usize = 4;
+    const R: usize = 0;
+    const B: usize = 2;
+}
+
+/// Dispatches to a function which is specialised for the [`RgbLayout`] of an RGB format.
+macro_rules! with_rgb_layout {
+    ($format:expr, $f:ident($($arg:expr),*)) => {
+        match $format {
+            PixelFormat::Rgb24 => $f::<Rgb24>($($arg),*),
+            PixelFormat::Bgr24 => $f::<Bgr24>($($arg),*),
+            PixelFormat::Rgba => $f::<Rgba>($($arg),*),
+            format => unreachable!("{:?} is not an RGB format", format),
+        }
+    };
+}
+
+fn fixed(value: f64) -> i32 {
+    (value * f64::from(1 << SHIFT)).round() as i32
+}
+
+#[inline(always)]
+fn clamp(value: i32) -> u8 {
+    value.max(0).min(255) as u8
+}
+
+/// Fixed point coefficients for converting YUV to RGB.
+struct YuvToRgb {
+    y_offset: i32,
+    y: i32,
+    r_v: i32,
+    g_u: i32,
+    g_v: i32,
+    b_u: i32,
+}
+
+impl YuvToRgb {
+    fn new(space: ColorSpace) -> YuvToRgb {
+        let (kr, kb) = space.matrix.weights();
+        let kg = 1.0 - kr - kb;
+        let (y_offset, y_scale, c_scale) = match space.range {
+            ColorRange::Full => (0, 1.0, 1.0),
+            ColorRange::Limited => (16, 255.0 / 219.0, 255.0 / 224.0),
+        };
+
+        YuvToRgb {
+            y_offset,
+            y: fixed(y_scale),
+            r_v: fixed(2.0 * (1.0 - kr) * c_scale),
+            g_u: fixed(2.0 * (1.0 - kb) * kb / kg * c_scale),
+            g_v: fixed(2.0 * (1.0 - kr) * kr / kg * c_scale),
+            b_u: fixed(2.0 * (1.0 - kb) * c_scale),
+        }
+    }
+
+    fn convert(&self, frame: &Frame, format: PixelFormat) -> Frame {
+        with_rgb_layout!(format, yuv_to_rgb(self, frame, format))
+    }
+
+    /// Converts a row of pixel pairs which share their chroma samples. Each item of `pairs` is
+    /// the Y value of both pixels followed by the U and V values.
+    #[inline(always)]
+    fn row<L, I>(&self, out: &mut [u8], pairs: I)
+    where
+        L: RgbLayout,
+        I: Iterator<Item = (u8, u8, u8, u8)>,
+    {
+        // The last chunk only holds one pixel if the width is odd
+        for (out, (y0, y1, u, v)) in out.chunks_mut(L::SIZE * 2).zip(pairs) {
+            let u = i32::from(u) - 128;
+            let v = i32::from(v) - 128;
+            let r = self.r_v * v;
+            let g = -self.g_u * u - self.g_v * v;
+            let b = self.b_u * u;
+
+            for (out, y) in out.chunks_exact_mut(L::SIZE).zip([y0, y1].iter()) {
+                let y = (i32::from(*y) - self.y_offset) * self.y + HALF;
+                out[L::R] = clamp((y + r) >> SHIFT);
+                out[1] = clamp((y + g) >> SHIFT);
+                out[L::B] = clamp((y + b) >> SHIFT);
+                if L::SIZE == 4 {
+                    out[3] = 255;
+                }
+            }
+        }
+    }
+}
+
+/// Pairs up the Y values of a row, repeating the last one if the width is odd.
+#[inline(always)]
+fn luma_pairs(y: &[u8]) -> impl Iterator<Item = (u8, u8)> + '_ {
+    y.chunks(2).map(|y| (y[0], y[y.len() - 1]))
+}
+
+fn yuv_to_rgb<L: RgbLayout>(coefficients: &YuvToRgb, frame: &Frame, format: PixelFormat) -> Frame {
+    let width = frame.width() as usize;
+    let height = frame.height() as usize;
+    let chroma_width = (width + 1) / 2;
+    let mut data = vec![0; width * height * L::SIZE];
+    let rows = data.chunks_exact_mut((width * L::SIZE).max(1)).enumerate();
+
+    match frame.format() {
+        PixelFormat::I420 => {
+            let (y_plane, y_stride) = frame.plane(0);
+            let (u_plane, c_stride) = frame.plane(1);
+            let (v_plane, _) = frame.plane(2);
+
+            for (row, out) in rows {
+                let y = &y_plane[row * y_stride..][..width];
+                let u = &u_plane[row / 2 * c_stride..][..chroma_width];
+                let v = &v_plane[row / 2 * c_stride..][..chroma_width];
+
+                coefficients.row::<L, _>(
+                    out,
+                    luma_pairs(y)
+                        .zip(u.iter().zip(v.iter()))
+                        .map(|((y0, y1), (u, v))| (y0, y1, *u, *v)),
+                );
+            }
+        }
+        PixelFormat::Nv12 => {
+            let (y_plane, y_stride) = frame.plane(0);
+            let (uv_plane, c_stride) = frame.plane(1);
+
+            for (row, out) in rows {
+                let y = &y_plane[row * y_stride..][..width];
+                let uv = &uv_plane[row / 2 * c_stride..][..chroma_width * 2];
+
+                coefficients.row::<L, _>(
+                    out,
+                    luma_pairs(y)
+                        .zip(uv.chunks_exact(2))
+                        .map(|((y0, y1), uv)| (y0, y1, uv[0], uv[1])),
+                );
+            }
+        }
+        PixelFormat::Yuyv => {
+            let (plane, stride) = frame.plane(0);
+
+            for (row, out) in rows {
+                let yuyv = &plane[row * stride..][..chroma_width * 4];
+
+                coefficients
+                    .row::<L, _>(out, yuyv.chunks_exact(4).map(|p| (p[0], p[2], p[1], p[3])));
+            }
+        }
+        _ => unreachable!("{:?} is not a YUV format", frame.format()),
+    }
+
+    Frame::packed(data, format, frame.width(), frame.height())
+}
+
+/// Fixed point coefficients for converting RGB to YUV.
+struct RgbToYuv {
+    y_offset: i32,
+    y: [i32; 3],
+    u: [i32; 3],
+    v: [i32; 3],
+}
+
+impl RgbToYuv {
+    fn new(space: ColorSpace) -> RgbToYuv {
+        let (kr, kb) = space.matrix.weights();
+        let kg = 1.0 - kr - kb;
+        let (y_offset, y_scale, c_scale) = match space.range {
+            ColorRange::Full => (0, 1.0, 1.0),
+            ColorRange::Limited => (16, 219.0 / 255.0, 224.0 / 255.0),
+        };
+        let u_scale = c_scale / (2.0 * (1.0 - kb));
+        let v_scale = c_scale / (2.0 * (1.0 - kr));
+
+        RgbToYuv {
+            y_offset,
+            y: [
+                fixed(kr * y_scale),
+                fixed(kg * y_scale),
+                fixed(kb * y_scale),
+            ],
+            u: [
+                fixed(-kr * u_scale),
+                fixed(-kg * u_scale),
+                fixed((1.0 - kb) * u_scale),
+            ],
+            v: [
+                fixed((1.0 - kr) * v_scale),
+                fixed(-kg * v_scale),
+                fixed(-kb * v_scale),
+            ],
+        }
+    }
+
+    fn convert(&self, frame: &Frame, format: PixelFormat) -> Frame {
+        with_rgb_layout!(frame.format(), rgb_to_yuv(self, frame, format))
+    }
+
+    #[inline(always)]
+    fn dot(coefficients: &[i32; 3], rgb: [i32; 3]) -> i32 {
+        (coefficients[0] * rgb[0] + coefficients[1] * rgb[1] + coefficients[2] * rgb[2] + HALF)
+            >> SHIFT
+    }
+
+    #[inline(always)]
+    fn luma(&self, rgb: [i32; 3]) -> u8 {
+        clamp(Self::dot(&self.y, rgb) + self.y_offset)
+    }
+
+    /// Returns the U and V values for the average of `1 << shift` pixels whose samples sum
+    /// to `rgb`.
+    #[inline(always)]
+    fn chroma(&self, rgb: [i32; 3], shift: i32) -> (u8, u8) {
+        let half = (1 << shift) >> 1;
+        let rgb = [
+            (rgb[0] + half) >> shift,
+            (rgb[1] + half) >> shift,
+            (rgb[2] + half) >> shift,
+        ];
+        (
+            clamp(Self::dot(&self.u, rgb) + 128),
+            clamp(Self::dot(&self.v, rgb) + 128),
+        )
+    }
+}
+
+/// Splits a row of RGB pixels into pairs, repeating the last pixel if the width is odd.
+#[inline(always)]
+fn rgb_pairs<L: RgbLayout>(row: &[u8]) -> impl Iterator<Item = ([i32; 3], [i32; 3])> + '_ {
+    let rgb = |p: &[u8]| [i32::from(p[L::R]), i32::from(p[1]), i32::from(p[L::B])];
+    row.chunks(L::SIZE * 2)
+        .map(move |pair| (rgb(pair), rgb(&pair[pair.len() - L::SIZE..])))
+}
+
+#[inline(always)]
+fn sum(a: [i32; 3], b: [i32; 3]) -> [i32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn rgb_to_yuv<L: RgbLayout>(coefficients: &RgbToYuv, frame: &Frame, format: PixelFormat) -> Frame {
+    let width = frame.width() as usize;
+    let height = frame.height() as usize;
+    let chroma_width = (width + 1) / 2;
+    let chroma_height = (height + 1) / 2;
+    let (plane, stride) = frame.plane(0);
+    let row = |row: usize| &plane[row * stride..][..width * L::SIZE];
+
+    let data = match format {
+        PixelFormat::I420 | PixelFormat::Nv12 => {
+            let luma_size = width * height;
+            let chroma_size = chroma_width * chroma_height;
+            let mut data = vec![0; luma_size + chroma_size * 2];
+            let (luma, chroma) = data.split_at_mut(luma_size);
+
+            // Work on two rows at a time so that each 2x2 block can be averaged for chroma.
+            // The last row is repeated if the height is odd.
+            for (chroma_row, luma) in luma.chunks_mut((width * 2).max(1)).enumerate() {
+                let top = row(chroma_row * 2);
+                let bottom = row((chroma_row * 2 + 1).min(height - 1));
+                let (luma_top, luma_bottom) = luma.split_at_mut(width.min(luma.len()));
+                let chroma_offset = chroma_row * chroma_width;
+
+                let blocks = rgb_pairs::<L>(top).zip(rgb_pairs::<L>(bottom));
+                for (x, ((p0, p1), (p2, p3))) in blocks.enumerate() {
+                    luma_top[x * 2] = coefficients.luma(p0);
+                    if let Some(y) = luma_top.get_mut(x * 2 + 1) {
+                        *y = coefficients.luma(p1);
+                    }
+                    if let Some(y) = luma_bottom.get_mut(x * 2) {
+                        *y = coefficients.luma(p2);
+                    }
+                    if let Some(y) = luma_bottom.get_mut(x * 2 + 1) {
+                        *y = coefficients.luma(p3);
+                    }
+
+                    let (u, v) = coefficients.chroma(sum(sum(p0, p1), sum(p2, p3)), 2);
+                    if format == PixelFormat::I420 {
+                        chroma[chroma_offset + x] = u;
+                        chroma[chroma_size + chroma_offset + x] = v;
+                    } else {
+                        let index = (chroma_offset + x) * 2;
+                        chroma[index] = u;
+                        chroma[index + 1] = v;
+                    }
+                }
+            }
+
+            data
+        }
+        PixelFormat::Yuyv => {
+            let row_bytes = chroma_width * 4;
+            let mut data = vec![0; row_bytes * height];
+
+            for (y, out) in data.chunks_exact_mut(row_bytes.max(1)).enumerate() {
+                let pairs = rgb_pairs::<L>(row(y));
+                for (out, (p0, p1)) in out.chunks_exact_mut(4).zip(pairs) {
+                    let (u, v) = coefficients.chroma(sum(p0, p1), 1);
+                    out[0] = coefficients.luma(p0);
+                    out[1] = u;
+                    out[2] = coefficients.luma(p1);
+                    out[3] = v;
+                }
+            }
+
+            data
+        }
+        _ => unreachable!("{:?} is not a YUV format", format),
+    };
+
+    Frame::packed(data, format, frame.width(), frame.height())
+}
+
+#[cfg(test)]
+fn solid_rgb(format: PixelFormat, width: u32, height: u32, rgb: [u8; 3]) -> Frame {
+    let (size, r, b) = match format {
+        PixelFormat::Rgb24 => (3, 0, 2),
+        PixelFormat::Bgr24 => (3, 2, 0),
+        _ => (4, 0, 2),
+    };
+    let mut data = vec![255; width as usize * height as usize * size];
+    for pixel in data.chunks_exact_mut(size) {
+        pixel[r] = rgb[0];
+        pixel[1] = rgb[1];
+        pixel[b] = rgb[2];
+    }
+    Frame::packed(data, format, width, height)
+}
+
+#[cfg(test)]
+fn assert_close(actual: &[u8], expected: &[u8], tolerance: u8) {
+    assert_eq!(actual.len(), expected.len());
+    for (i, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
+        assert!(
+            (i32::from(*a) - i32::from(*e)).abs() <= i32::from(tolerance),
+            "byte {}: {} is not close to {}",
+            i,
+            a,
+            e
+        );
+    }
+}
+
+#[test]
+fn test_convert_known_values() {
+    let space = ColorSpace::default();
+
+    // Pure red in BT.601 full range is Y=76, U=85, V=255
+    let red = solid_rgb(PixelFormat::Rgb24, 2, 2, [255, 0, 0]);
+    let yuv = convert(&red, PixelFormat::I420, space).unwrap();
+    assert_close(yuv.data(), &[76, 76, 76, 76, 85, 255], 1);
+
+    // White and black in limited range
+    let limited = ColorSpace {
+        matrix: ColorMatrix::Bt709,
+        range: ColorRange::Limited,
+    };
+    let white = solid_rgb(PixelFormat::Rgb24, 2, 1, [255, 255, 255]);
+    let yuv = convert(&white, PixelFormat::Yuyv, limited).unwrap();
+    assert_eq!(yuv.data(), &[235, 128, 235, 128]);
+
+    let black = Frame::packed(vec![16, 128, 16, 128], PixelFormat::Yuyv, 2, 1);
+    let rgb = convert(&black, PixelFormat::Rgba, limited).unwrap();
+    assert_eq!(rgb.data(), &[0, 0, 0, 255, 0, 0, 0, 255]);
+}
+
+#[test]
+fn test_convert_round_trip() {
+    let colours = [
+        [0, 0, 0],
+        [255, 255, 255],
+        [200, 30, 60],
+        [10, 180, 90],
+        [40, 70, 220],
+    ];
+    let matrices = [ColorMatrix::Bt601, ColorMatrix::Bt709];
+    let ranges = [ColorRange::Full, ColorRange::Limited];
+    let yuv_formats = [PixelFormat::I420, PixelFormat::Nv12, PixelFormat::Yuyv];
+    let rgb_formats = [PixelFormat::Rgb24, PixelFormat::Bgr24, PixelFormat::Rgba];
+
+    for &colour in colours.iter() {
+        for &matrix in matrices.iter() {
+            for &range in ranges.iter() {
+                for &yuv_format in yuv_formats.iter() {
+                    for &rgb_format in rgb_formats.iter() {
+                        let space = ColorSpace { matrix, range };
+                        let original = solid_rgb(rgb_format, 5, 3, colour);
+                        let yuv = convert(&original, yuv_format, space).unwrap();
+                        assert!(yuv.is_packed());
+                        let rgb = convert(&yuv, rgb_format, space).unwrap();
+
+                        assert_close(rgb.data(), original.data(), 3);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_convert_padded_input() {
+    // A 100x75 I420 frame as the camera produces it, padded to 128x80
+    let mut data = vec![0; 128 * 80 * 3 / 2];
+    let (luma, chroma) = data.split_at_mut(128 * 80);
+    for row in 0..75 {
+        for x in 0..100 {
+            luma[row * 128 + x] = 128;
+        }
+    }
+    for value in chroma.iter_mut() {
+        *value = 128;
+    }
+    let frame = Frame::new(data, PixelFormat::I420, 100, 75);
+
+    let rgb = convert(&frame, PixelFormat::Rgb24, ColorSpace::default()).unwrap();
+
+    assert!(rgb.is_packed());
+    assert_eq!(rgb.data(), &vec![128; 100 * 75 * 3][..]);
+}
+
+#[test]
+fn test_convert_unsupported() {
+    let frame = solid_rgb(PixelFormat::Rgb24, 2, 2, [0, 0, 0]);
+    assert!(convert(&frame, PixelFormat::Rgba, ColorSpace::default()).is_none());
+}
@@ -6,35 +6,191 @@ use std::fmt;
 use std::io;
 use std::sync::mpsc;
 
+/// Status codes returned by MMAL functions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MmalStatus {
+    /// `MMAL_SUCCESS`
+    Success,
+    /// `MMAL_ENOMEM`: out of memory, usually GPU memory.
+    NoMemory,
+    /// `MMAL_ENOSPC`: out of resources other than memory. This is what enabling the camera
+    /// returns when another process is using it, which is reported as
+    /// [`ErrorKind::CameraBusy`].
+    NoSpace,
+    /// `MMAL_EINVAL`: an argument is invalid.
+    InvalidArgument,
+    /// `MMAL_ENOSYS`: the function isn't implemented.
+    NotImplemented,
+    /// `MMAL_ENOENT`: no such file or directory.
+    NotFound,
+    /// `MMAL_ENXIO`: no such device or address.
+    NoDevice,
+    /// `MMAL_EIO`: I/O error.
+    Io,
+    /// `MMAL_ESPIPE`: illegal seek.
+    IllegalSeek,
+    /// `MMAL_ECORRUPT`: the data is corrupt.
+    Corrupt,
+    /// `MMAL_ENOTREADY`: the component isn't ready.
+    NotReady,
+    /// `MMAL_ECONFIG`: the component isn't configured.
+    NotConfigured,
+    /// `MMAL_EISCONN`: the port is already connected.
+    AlreadyConnected,
+    /// `MMAL_ENOTCONN`: the port is disconnected.
+    NotConnected,
+    /// `MMAL_EAGAIN`: the resource is temporarily unavailable, try again later.
+    Again,
+    /// `MMAL_EFAULT`: bad address.
+    BadAddress,
+    /// A status this library doesn't know about.
+    Unknown(MMAL_STATUS_T::Type),
+    /// Hints that destructuring should not be exhaustive, as with [`ErrorKind`].
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+impl MmalStatus {
+    /// The raw MMAL status code.
+    pub fn code(self) -> MMAL_STATUS_T::Type {
+        match self {
+            MmalStatus::Success => MMAL_STATUS_T::MMAL_SUCCESS,
+            MmalStatus::NoMemory => MMAL_STATUS_T::MMAL_ENOMEM,
+            MmalStatus::NoSpace => MMAL_STATUS_T::MMAL_ENOSPC,
+            MmalStatus::InvalidArgument => MMAL_STATUS_T::MMAL_EINVAL,
+            MmalStatus::NotImplemented => MMAL_STATUS_T::MMAL_ENOSYS,
+            MmalStatus::NotFound => MMAL_STATUS_T::MMAL_ENOENT,
+            MmalStatus::NoDevice => MMAL_STATUS_T::MMAL_ENXIO,
+            MmalStatus::Io => MMAL_STATUS_T::MMAL_EIO,
+            MmalStatus::IllegalSeek => MMAL_STATUS_T::MMAL_ESPIPE,
+            MmalStatus::Corrupt => MMAL_STATUS_T::MMAL_ECORRUPT,
+            MmalStatus::NotReady => MMAL_STATUS_T::MMAL_ENOTREADY,
+            MmalStatus::NotConfigured => MMAL_STATUS_T::MMAL_ECONFIG,
+            MmalStatus::AlreadyConnected => MMAL_STATUS_T::MMAL_EISCONN,
+            MmalStatus::NotConnected => MMAL_STATUS_T::MMAL_ENOTCONN,
+            MmalStatus::Again => MMAL_STATUS_T::MMAL_EAGAIN,
+            MmalStatus::BadAddress => MMAL_STATUS_T::MMAL_EFAULT,
+            MmalStatus::Unknown(code) => code,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The name MMAL uses for the status, e.g. `"ENOMEM"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            MmalStatus::Success => "SUCCESS",
+            MmalStatus::NoMemory => "ENOMEM",
+            MmalStatus::NoSpace => "ENOSPC",
+            MmalStatus::InvalidArgument => "EINVAL",
+            MmalStatus::NotImplemented => "ENOSYS",
+            MmalStatus::NotFound => "ENOENT",
+            MmalStatus::NoDevice => "ENXIO",
+            MmalStatus::Io => "EIO",
+            MmalStatus::IllegalSeek => "ESPIPE",
+            MmalStatus::Corrupt => "ECORRUPT",
+            MmalStatus::NotReady => "ENOTREADY",
+            MmalStatus::NotConfigured => "ECONFIG",
+            MmalStatus::AlreadyConnected => "EISCONN",
+            MmalStatus::NotConnected => "ENOTCONN",
+            MmalStatus::Again => "EAGAIN",
+            MmalStatus::BadAddress => "EFAULT",
+            MmalStatus::Unknown(_) => "UNKNOWN",
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<MMAL_STATUS_T::Type> for MmalStatus {
+    fn from(code: MMAL_STATUS_T::Type) -> MmalStatus {
+        match code {
+            MMAL_STATUS_T::MMAL_SUCCESS => MmalStatus::Success,
+            MMAL_STATUS_T::MMAL_ENOMEM => MmalStatus::NoMemory,
+            MMAL_STATUS_T::MMAL_ENOSPC => MmalStatus::NoSpace,
+            MMAL_STATUS_T::MMAL_EINVAL => MmalStatus::InvalidArgument,
+            MMAL_STATUS_T::MMAL_ENOSYS => MmalStatus::NotImplemented,
+            MMAL_STATUS_T::MMAL_ENOENT => MmalStatus::NotFound,
+            MMAL_STATUS_T::MMAL_ENXIO => MmalStatus::NoDevice,
+            MMAL_STATUS_T::MMAL_EIO => MmalStatus::Io,
+            MMAL_STATUS_T::MMAL_ESPIPE => MmalStatus::IllegalSeek,
+            MMAL_STATUS_T::MMAL_ECORRUPT => MmalStatus::Corrupt,
+            MMAL_STATUS_T::MMAL_ENOTREADY => MmalStatus::NotReady,
+            MMAL_STATUS_T::MMAL_ECONFIG => MmalStatus::NotConfigured,
+            MMAL_STATUS_T::MMAL_EISCONN => MmalStatus::AlreadyConnected,
+            MMAL_STATUS_T::MMAL_ENOTCONN => MmalStatus::NotConnected,
+            MMAL_STATUS_T::MMAL_EAGAIN => MmalStatus::Again,
+            MMAL_STATUS_T::MMAL_EFAULT => MmalStatus::BadAddress,
+            code => MmalStatus::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for MmalStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            MmalStatus::Success => "success",
+            MmalStatus::NoMemory => "out of memory",
+            MmalStatus::NoSpace => "out of resources",
+            MmalStatus::InvalidArgument => "invalid argument",
+            MmalStatus::NotImplemented => "not implemented",
+            MmalStatus::NotFound => "not found",
+            MmalStatus::NoDevice => "no such device",
+            MmalStatus::Io => "I/O error",
+            MmalStatus::IllegalSeek => "illegal seek",
+            MmalStatus::Corrupt => "data is corrupt",
+            MmalStatus::NotReady => "component is not ready",
+            MmalStatus::NotConfigured => "component is not configured",
+            MmalStatus::AlreadyConnected => "port is already connected",
+            MmalStatus::NotConnected => "port is not connected",
+            MmalStatus::Again => "resource temporarily unavailable",
+            MmalStatus::BadAddress => "bad address",
+            MmalStatus::Unknown(code) => return write!(f, "unknown status {}", code),
+            _ => unreachable!(),
+        };
+        write!(f, "{} ({})", description, self.name())
+    }
+}
+
 /// Represents an error from the MMAL library.
 pub struct MmalError {
     message: String,
-    status_code: MMAL_STATUS_T::Type,
+    status: MmalStatus,
+    stage: Option<Stage>,
 }
 
 impl MmalError {
     pub fn with_status(message: String, status_code: MMAL_STATUS_T::Type) -> MmalError {
         MmalError {
             message,
-            status_code,
+            status: status_code.into(),
+            stage: None,
         }
     }
 
+    /// Records the part of the pipeline that returned the status.
+    pub fn with_stage(mut self, stage: Stage) -> MmalError {
+        self.stage = Some(stage);
+        self
+    }
+
+    /// The part of the pipeline that returned the status, if known.
+    pub fn stage(&self) -> Option<Stage> {
+        self.stage
+    }
+
+    /// The name of the status MMAL returned, e.g. `"ENOMEM"`.
     pub fn status(&self) -> &str {
-        unsafe {
-            ::std::ffi::CStr::from_ptr(ffi::mmal_status_to_string(self.status_code))
-                .to_str()
-                .unwrap()
-        }
+        self.status.name()
+    }
+
+    /// The status MMAL returned.
+    pub fn status_kind(&self) -> MmalStatus {
+        self.status
     }
 }
 
 #[test]
 fn test_camera_error_status() {
-    let mut err = MmalError {
-        message: "testing".to_string(),
-        status_code: 0,
-    };
+    let mut err = MmalError::with_status("testing".to_string(), 0);
 
     {
         let result = err.status();
@@ -42,13 +198,13 @@ fn test_camera_error_status() {
     }
 
     {
-        err.status_code = 1;
+        err.status = 1.into();
         let result = err.status();
         assert_eq!(result, "ENOMEM");
     }
 
     {
-        err.status_code = 3;
+        err.status = 3.into();
         let result = err.status();
         assert_eq!(result, "EINVAL");
     }
@@ -56,39 +212,48 @@ fn test_camera_error_status() {
     // Note that there are other errors
 }
 
+#[test]
+fn test_mmal_status_round_trip() {
+    for code in 0..=16 {
+        let status = MmalStatus::from(code);
+        assert_eq!(status.code(), code);
+        assert_eq!(status == MmalStatus::Unknown(code), code == 16);
+    }
+
+    let err = MmalError::with_status("Unable to create camera".to_owned(), 6);
+    assert_eq!(err.status_kind(), MmalStatus::NoDevice);
+    assert_eq!(err.to_string(), "Unable to create camera Status: ENXIO");
+    assert_eq!(
+        MmalStatus::Unknown(MMAL_STATUS_T::MMAL_STATUS_MAX).to_string(),
+        "unknown status 2147483647"
+    );
+    assert_eq!(MmalStatus::NoMemory.to_string(), "out of memory (ENOMEM)");
+}
+
 impl fmt::Display for MmalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(&self.message)?;
 
         // Use 0 value (MMAL_STATUS) to indicate no status_code provided
-        if self.status_code != MMAL_STATUS_T::MMAL_SUCCESS {
-            let s = self.status();
-            write!(f, " Status: {}", s)
+        if self.status != MmalStatus::Success {
+            write!(f, " Status: {}", self.status())
         } else {
             Ok(())
         }
     }
 }
 
-impl error::Error for MmalError {
-    fn description(&self) -> &str {
-        // TODO: should we include the status here? If so, how? &str may make that hard.
-        &self.message
-    }
-
-    fn cause(&self) -> Option<&dyn error::Error> {
-        None
-    }
-}
+impl error::Error for MmalError {}
 
 impl fmt::Debug for MmalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "MmalError {{ message: {}, status: {}, status_code: {} }}",
+            "MmalError {{ message: {}, status: {}, status_code: {}, stage: {:?} }}",
             self.message,
             self.status(),
-            self.status_code,
+            self.status.code(),
+            self.stage,
         )
     }
 }
@@ -109,12 +274,73 @@ impl CameraError {
     }
 }
 
+/// The part of the camera pipeline that an error came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    Camera,
+    Encoder,
+    Preview,
+    /// A pool of buffers for a port.
+    Pool,
+    /// A connection between two components.
+    Connection,
+    Splitter,
+    Resizer,
+    /// Hints that destructuring should not be exhaustive, as with [`ErrorKind`].
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Camera => "camera",
+            Stage::Encoder => "encoder",
+            Stage::Preview => "preview",
+            Stage::Pool => "buffer pool",
+            Stage::Connection => "connection",
+            Stage::Splitter => "splitter",
+            Stage::Resizer => "resizer",
+            _ => unreachable!(),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum ErrorKind {
     Mmal(MmalError),
     Recv(mpsc::RecvError),
     Io(io::Error),
-
+    /// Another process, such as raspistill, is using the camera. MMAL reports this as
+    /// [`MmalStatus::NoSpace`] when the camera is enabled.
+    ///
+    /// This only comes from the camera itself, so it carries no stage;
+    /// [`stage`](ErrorKind::stage) returns [`Stage::Camera`].
+    CameraBusy,
+    /// There is no camera connected, or the camera isn't enabled. MMAL reports this as
+    /// [`MmalStatus::NotFound`] when the camera is created.
+    ///
+    /// Like [`CameraBusy`](ErrorKind::CameraBusy), this carries no stage and
+    /// [`stage`](ErrorKind::stage) returns [`Stage::Camera`].
+    NoCamera,
+    /// The camera isn't ready for the operation, e.g. an image was requested before the
+    /// camera was activated.
+    InvalidState(Stage),
+    /// An argument was out of range or doesn't suit the stage, such as too few buffers for a
+    /// port. The message says which argument.
+    InvalidArgument(Stage, String),
+    /// A pool of buffers couldn't be created or ran out of buffers.
+    PoolExhausted(Stage),
+    /// The camera didn't respond in time.
+    Timeout(Stage),
+    /// The camera started a capture but didn't produce an image.
+    CaptureFailed(Stage),
+    /// Too many captures are in progress, because earlier images haven't been received or
+    /// their buffers haven't been released.
+    QueueFull(Stage),
+    /// A stream's consumer fell behind and buffers were dropped, with
+    /// [`Backpressure::Error`](crate::Backpressure::Error).
+    Overflow(Stage),
     /// Hints that destructuring should not be exhaustive.
     ///
     /// This enum may grow additional variants, so this makes sure clients
@@ -124,37 +350,72 @@ pub enum ErrorKind {
     __Nonexhaustive,
 }
 
+impl ErrorKind {
+    /// The part of the pipeline that failed, if known.
+    pub fn stage(&self) -> Option<Stage> {
+        match *self {
+            ErrorKind::CameraBusy | ErrorKind::NoCamera => Some(Stage::Camera),
+            ErrorKind::InvalidState(stage)
+            | ErrorKind::InvalidArgument(stage, _)
+            | ErrorKind::PoolExhausted(stage)
+            | ErrorKind::Timeout(stage)
+            | ErrorKind::CaptureFailed(stage)
+            | ErrorKind::QueueFull(stage)
+            | ErrorKind::Overflow(stage) => Some(stage),
+            ErrorKind::Mmal(ref err) => err.stage(),
+            ErrorKind::Recv(_) | ErrorKind::Io(_) => None,
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl fmt::Display for CameraError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *(self.kind()) {
             ErrorKind::Mmal(ref err) => write!(f, "MMAL error: {}", err),
             ErrorKind::Recv(ref err) => write!(f, "Recv error: {}", err),
             ErrorKind::Io(ref err) => write!(f, "IO error: {}", err),
+            ErrorKind::CameraBusy => f.write_str("The camera is in use by another process"),
+            ErrorKind::NoCamera => f.write_str("No camera found"),
+            ErrorKind::InvalidState(stage) => write!(f, "The {} is not ready", stage),
+            ErrorKind::InvalidArgument(stage, ref message) => {
+                write!(f, "Invalid argument for the {}: {}", stage, message)
+            }
+            ErrorKind::PoolExhausted(stage) => write!(f, "The {} has no buffers left", stage),
+            ErrorKind::Timeout(stage) => write!(f, "Timed out waiting for the {}", stage),
+            ErrorKind::CaptureFailed(stage) => write!(f, "The {} failed to capture", stage),
+            ErrorKind::QueueFull(stage) => {
+                write!(f, "Too many captures are waiting for the {}", stage)
+            }
+            ErrorKind::Overflow(stage) => {
+                write!(
+                    f,
+                    "Buffers from the {} were dropped before being received",
+                    stage
+                )
+            }
             _ => unreachable!(),
         }
     }
 }
 
 impl error::Error for CameraError {
-    fn description(&self) -> &str {
-        match *(self.kind()) {
-            ErrorKind::Mmal(ref err) => err.description(),
-            ErrorKind::Recv(ref err) => err.description(),
-            ErrorKind::Io(ref err) => err.description(),
-            _ => unreachable!(),
-        }
-    }
-
-    fn cause(&self) -> Option<&dyn error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *(self.kind()) {
             ErrorKind::Mmal(ref err) => Some(err),
             ErrorKind::Recv(ref err) => Some(err),
             ErrorKind::Io(ref err) => Some(err),
-            _ => unreachable!(),
+            _ => None,
         }
     }
 }
 
+impl From<ErrorKind> for CameraError {
+    fn from(kind: ErrorKind) -> CameraError {
+        CameraError(Box::new(kind))
+    }
+}
+
 impl From<MmalError> for ErrorKind {
     fn from(err: MmalError) -> ErrorKind {
         ErrorKind::Mmal(err)
@@ -178,3 +439,38 @@ impl From<io::Error> for CameraError {
         CameraError(Box::new(ErrorKind::Io(err)))
     }
 }
+
+#[test]
+fn test_camera_error_source() {
+    use std::error::Error;
+
+    let err = CameraError::from(MmalError::with_status(
+        "Unable to enable camera".to_owned(),
+        3,
+    ));
+    assert_eq!(err.kind().stage(), None);
+    assert_eq!(
+        err.source().unwrap().to_string(),
+        "Unable to enable camera Status: EINVAL"
+    );
+
+    let err = CameraError::from(
+        MmalError::with_status("Unable to enable preview".to_owned(), 3).with_stage(Stage::Preview),
+    );
+    assert_eq!(err.kind().stage(), Some(Stage::Preview));
+
+    let err = CameraError::from(ErrorKind::Timeout(Stage::Encoder));
+    assert_eq!(err.kind().stage(), Some(Stage::Encoder));
+    assert!(err.source().is_none());
+    assert_eq!(err.to_string(), "Timed out waiting for the encoder");
+
+    let err = CameraError::from(ErrorKind::InvalidArgument(
+        Stage::Splitter,
+        "got 5 outputs".to_owned(),
+    ));
+    assert_eq!(err.kind().stage(), Some(Stage::Splitter));
+    assert_eq!(
+        err.to_string(),
+        "Invalid argument for the splitter: got 5 outputs"
+    );
+}
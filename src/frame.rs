@@ -0,0 +1,477 @@
+use mmal_sys as ffi;
+
+/// Layout of the pixels in a raw (unencoded) image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 8 bits each of red, green and blue per pixel.
+    Rgb24,
+    /// 8 bits each of blue, green and red per pixel.
+    Bgr24,
+    /// 8 bits each of red, green, blue and alpha per pixel.
+    Rgba,
+    /// Planar YUV 4:2:0. A full resolution Y plane followed by quarter resolution U and V planes.
+    I420,
+    /// Semi-planar YUV 4:2:0. A full resolution Y plane followed by a quarter resolution
+    /// plane of interleaved U and V samples.
+    Nv12,
+    /// Packed YUV 4:2:2. Each pair of pixels is stored as Y0, U, Y1, V.
+    Yuyv,
+}
+
+impl PixelFormat {
+    /// Returns the MMAL encoding (FourCC) for this format.
+    pub fn encoding(self) -> u32 {
+        match self {
+            PixelFormat::Rgb24 => ffi::MMAL_ENCODING_RGB24,
+            PixelFormat::Bgr24 => ffi::MMAL_ENCODING_BGR24,
+            PixelFormat::Rgba => ffi::MMAL_ENCODING_RGBA,
+            PixelFormat::I420 => ffi::MMAL_ENCODING_I420,
+            PixelFormat::Nv12 => ffi::MMAL_ENCODING_NV12,
+            PixelFormat::Yuyv => ffi::MMAL_ENCODING_YUYV,
+        }
+    }
+
+    /// Returns the format matching an MMAL encoding, if it is a raw format.
+    pub fn from_encoding(encoding: u32) -> Option<PixelFormat> {
+        match encoding {
+            ffi::MMAL_ENCODING_RGB24 => Some(PixelFormat::Rgb24),
+            ffi::MMAL_ENCODING_BGR24 => Some(PixelFormat::Bgr24),
+            ffi::MMAL_ENCODING_RGBA => Some(PixelFormat::Rgba),
+            ffi::MMAL_ENCODING_I420 => Some(PixelFormat::I420),
+            ffi::MMAL_ENCODING_NV12 => Some(PixelFormat::Nv12),
+            ffi::MMAL_ENCODING_YUYV => Some(PixelFormat::Yuyv),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes used by a row of `width` pixels in the first (or only) plane.
+    pub(crate) fn row_bytes(self, width: u32) -> usize {
+        let width = width as usize;
+        match self {
+            PixelFormat::Rgb24 | PixelFormat::Bgr24 => width * 3,
+            PixelFormat::Rgba => width * 4,
+            PixelFormat::I420 | PixelFormat::Nv12 => width,
+            // Pixels are stored in pairs so an odd width still needs a whole pair.
+            PixelFormat::Yuyv => (width + 1) / 2 * 4,
+        }
+    }
+
+    /// Number of bytes needed for an image with the given row stride (in bytes) and
+    /// number of rows in the first plane.
+    fn buffer_size(self, stride: usize, slice_height: usize) -> usize {
+        match self {
+            PixelFormat::Rgb24 | PixelFormat::Bgr24 | PixelFormat::Rgba | PixelFormat::Yuyv => {
+                stride * slice_height
+            }
+            // Y plane plus U and V planes at half the stride and half the height each.
+            PixelFormat::I420 => {
+                stride * slice_height + 2 * self.chroma_stride(stride) * chroma_rows(slice_height)
+            }
+            // Y plane plus a single interleaved UV plane at the same stride and half the height.
+            PixelFormat::Nv12 => {
+                stride * slice_height + self.chroma_stride(stride) * chroma_rows(slice_height)
+            }
+        }
+    }
+
+    /// Number of bytes between the start of each row of the chroma plane(s).
+    ///
+    /// Odd widths are rounded up so that a packed frame still has room for the last
+    /// chroma sample.
+    fn chroma_stride(self, stride: usize) -> usize {
+        match self {
+            PixelFormat::I420 => (stride + 1) / 2,
+            PixelFormat::Nv12 => (stride + 1) / 2 * 2,
+            _ => 0,
+        }
+    }
+}
+
+/// Number of rows in each chroma plane of a 4:2:0 image.
+fn chroma_rows(rows: usize) -> usize {
+    (rows + 1) / 2
+}
+
+/// A raw image captured from the camera.
+///
+/// The camera pads each row to a multiple of 32 pixels and the number of rows to a multiple
+/// of 16. A `Frame` keeps track of that padding so that it can be removed with
+/// [`to_packed`] or [`pack`].
+///
+/// [`to_packed`]: Frame::to_packed
+/// [`pack`]: Frame::pack
+#[derive(Clone, Debug)]
+pub struct Frame {
+    data: Vec<u8>,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride: usize,
+    slice_height: usize,
+}
+
+impl Frame {
+    /// Creates a frame from a buffer laid out the way the camera writes it, with the width
+    /// aligned to 32 pixels and the height aligned to 16 rows.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is too small to hold an image of this size.
+    pub fn new(data: Vec<u8>, format: PixelFormat, width: u32, height: u32) -> Frame {
+        let (stride, slice_height) = camera_layout(format, width, height);
+        Frame::with_layout(data, format, width, height, stride, slice_height)
+    }
+
+    /// Number of bytes the camera uses for an image of this size, including padding.
+    pub(crate) fn camera_size(format: PixelFormat, width: u32, height: u32) -> usize {
+        let (stride, slice_height) = camera_layout(format, width, height);
+        format.buffer_size(stride, slice_height)
+    }
+
+    /// Creates a frame from a buffer which contains no padding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is too small to hold an image of this size.
+    pub fn packed(data: Vec<u8>, format: PixelFormat, width: u32, height: u32) -> Frame {
+        let stride = format.row_bytes(width);
+        Frame::with_layout(data, format, width, height, stride, height as usize)
+    }
+
+    /// Creates a frame from a buffer with an explicit layout.
+    ///
+    /// `stride` is the number of bytes between the start of each row of the first plane and
+    /// `slice_height` is the number of rows in the first plane, including padding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layout is smaller than the image or `data` is too small to hold it.
+    pub fn with_layout(
+        data: Vec<u8>,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+        stride: usize,
+        slice_height: usize,
+    ) -> Frame {
+        assert!(
+            stride >= format.row_bytes(width),
+            "stride {} is too small for width {}",
+            stride,
+            width
+        );
+        assert!(
+            slice_height >= height as usize,
+            "slice height {} is too small for height {}",
+            slice_height,
+            height
+        );
+        let required = format.buffer_size(stride, slice_height);
+        assert!(
+            data.len() >= required,
+            "frame data is {} bytes but {} are required",
+            data.len(),
+            required
+        );
+
+        Frame {
+            data,
+            format,
+            width,
+            height,
+            stride,
+            slice_height,
+        }
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Number of bytes between the start of each row of the first plane.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Number of rows in the first plane, including padding.
+    pub fn slice_height(&self) -> usize {
+        self.slice_height
+    }
+
+    /// The raw bytes of the image, including any padding.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Unwraps the frame into its raw bytes, including any padding.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Indicates if the frame contains no padding.
+    pub fn is_packed(&self) -> bool {
+        self.stride == self.format.row_bytes(self.width)
+            && self.slice_height == self.height as usize
+            && self.data.len() == self.format.buffer_size(self.stride, self.slice_height)
+    }
+
+    /// Returns a copy of this frame with the row and plane padding removed.
+    pub fn to_packed(&self) -> Frame {
+        let (stride, slice_height) = self.packed_layout();
+        let mut data = Vec::with_capacity(self.format.buffer_size(stride, slice_height));
+
+        for (src, rows, row_len) in self.planes() {
+            for row in 0..rows {
+                let start = src.offset + row * src.stride;
+                data.extend_from_slice(&self.data[start..start + row_len]);
+            }
+        }
+
+        Frame {
+            data,
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            stride,
+            slice_height,
+        }
+    }
+
+    /// Removes the row and plane padding from this frame without allocating.
+    pub fn pack(&mut self) {
+        let (stride, slice_height) = self.packed_layout();
+        let mut dest = 0;
+
+        // Packed rows are never longer than padded rows so each row is only ever copied
+        // backwards, into space which has already been read.
+        for (src, rows, row_len) in self.planes() {
+            for row in 0..rows {
+                let start = src.offset + row * src.stride;
+                self.data.copy_within(start..start + row_len, dest);
+                dest += row_len;
+            }
+        }
+
+        self.data.truncate(dest);
+        self.stride = stride;
+        self.slice_height = slice_height;
+    }
+
+    /// Returns the data starting at the given plane along with that plane's stride.
+    pub(crate) fn plane(&self, index: usize) -> (&[u8], usize) {
+        let (plane, _, _) = self.planes().swap_remove(index);
+        (&self.data[plane.offset..], plane.stride)
+    }
+
+    fn packed_layout(&self) -> (usize, usize) {
+        (self.format.row_bytes(self.width), self.height as usize)
+    }
+
+    /// Describes each plane as its location in the padded data, the number of rows to keep
+    /// and the number of bytes to keep from each row.
+    fn planes(&self) -> Vec<(Plane, usize, usize)> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let luma = Plane {
+            offset: 0,
+            stride: self.stride,
+        };
+        let luma_size = self.stride * self.slice_height;
+
+        match self.format {
+            PixelFormat::Rgb24 | PixelFormat::Bgr24 | PixelFormat::Rgba | PixelFormat::Yuyv => {
+                vec![(luma, height, self.format.row_bytes(self.width))]
+            }
+            PixelFormat::I420 => {
+                let chroma_stride = self.format.chroma_stride(self.stride);
+                let chroma_size = chroma_stride * chroma_rows(self.slice_height);
+                let chroma_width = (width + 1) / 2;
+                let chroma_height = chroma_rows(height);
+                vec![
+                    (luma, height, width),
+                    (
+                        Plane {
+                            offset: luma_size,
+                            stride: chroma_stride,
+                        },
+                        chroma_height,
+                        chroma_width,
+                    ),
+                    (
+                        Plane {
+                            offset: luma_size + chroma_size,
+                            stride: chroma_stride,
+                        },
+                        chroma_height,
+                        chroma_width,
+                    ),
+                ]
+            }
+            PixelFormat::Nv12 => vec![
+                (luma, height, width),
+                (
+                    Plane {
+                        offset: luma_size,
+                        stride: self.format.chroma_stride(self.stride),
+                    },
+                    chroma_rows(height),
+                    (width + 1) / 2 * 2,
+                ),
+            ],
+        }
+    }
+}
+
+/// Returns the stride and slice height the camera uses for an image of this size.
+fn camera_layout(format: PixelFormat, width: u32, height: u32) -> (usize, usize) {
+    (
+        format.row_bytes(ffi::vcos_align_up(width, 32)),
+        ffi::vcos_align_up(height, 16) as usize,
+    )
+}
+
+struct Plane {
+    offset: usize,
+    stride: usize,
+}
+
+/// Builds a padded frame where every byte holds its (plane, row, column) position so that
+/// packing mistakes show up as wrong values. Padding bytes are 0xff.
+#[cfg(test)]
+fn padded_frame(format: PixelFormat, width: u32, height: u32) -> Frame {
+    let (stride, slice_height) = camera_layout(format, width, height);
+    let mut data = vec![0xffu8; format.buffer_size(stride, slice_height)];
+    let frame = Frame {
+        data: Vec::new(),
+        format,
+        width,
+        height,
+        stride,
+        slice_height,
+    };
+
+    for (index, (plane, rows, row_len)) in frame.planes().into_iter().enumerate() {
+        for row in 0..rows {
+            for col in 0..row_len {
+                data[plane.offset + row * plane.stride + col] =
+                    ((index * 100 + row * 7 + col) % 0xfe) as u8;
+            }
+        }
+    }
+
+    Frame { data, ..frame }
+}
+
+#[cfg(test)]
+fn expected_packed(frame: &Frame) -> Vec<u8> {
+    let mut expected = Vec::new();
+    for (index, (_, rows, row_len)) in frame.planes().into_iter().enumerate() {
+        for row in 0..rows {
+            for col in 0..row_len {
+                expected.push(((index * 100 + row * 7 + col) % 0xfe) as u8);
+            }
+        }
+    }
+    expected
+}
+
+#[test]
+fn test_frame_to_packed() {
+    let formats = [
+        PixelFormat::Rgb24,
+        PixelFormat::Bgr24,
+        PixelFormat::Rgba,
+        PixelFormat::I420,
+        PixelFormat::Nv12,
+        PixelFormat::Yuyv,
+    ];
+    let sizes = [(100, 75), (96, 96), (1, 1), (33, 17), (2592, 1944)];
+
+    for &format in formats.iter() {
+        for &(width, height) in sizes.iter() {
+            let frame = padded_frame(format, width, height);
+            let packed = frame.to_packed();
+
+            assert!(packed.is_packed(), "{:?} {}x{}", format, width, height);
+            assert_eq!(packed.width(), width);
+            assert_eq!(packed.height(), height);
+            assert_eq!(
+                packed.data(),
+                &expected_packed(&frame)[..],
+                "{:?} {}x{}",
+                format,
+                width,
+                height
+            );
+        }
+    }
+}
+
+#[test]
+fn test_frame_pack_in_place() {
+    let formats = [
+        PixelFormat::Rgb24,
+        PixelFormat::Rgba,
+        PixelFormat::I420,
+        PixelFormat::Nv12,
+        PixelFormat::Yuyv,
+    ];
+
+    for &format in formats.iter() {
+        let mut frame = padded_frame(format, 100, 75);
+        let expected = frame.to_packed();
+
+        frame.pack();
+
+        assert!(frame.is_packed());
+        assert_eq!(frame.stride(), expected.stride());
+        assert_eq!(frame.slice_height(), 75);
+        assert_eq!(frame.data(), expected.data());
+    }
+}
+
+#[test]
+fn test_frame_packed_sizes() {
+    assert_eq!(
+        padded_frame(PixelFormat::Rgb24, 100, 75)
+            .to_packed()
+            .data()
+            .len(),
+        100 * 75 * 3
+    );
+    assert_eq!(
+        padded_frame(PixelFormat::I420, 100, 75)
+            .to_packed()
+            .data()
+            .len(),
+        100 * 75 + 2 * (50 * 38)
+    );
+    assert_eq!(
+        padded_frame(PixelFormat::Nv12, 100, 75)
+            .to_packed()
+            .data()
+            .len(),
+        100 * 75 + 100 * 38
+    );
+}
+
+#[test]
+fn test_frame_already_packed() {
+    let frame = padded_frame(PixelFormat::Rgb24, 96, 96);
+    assert!(frame.is_packed());
+    assert_eq!(frame.to_packed().data(), frame.data());
+}
+
+#[test]
+#[should_panic]
+fn test_frame_too_small() {
+    Frame::new(vec![0; 100 * 75 * 3], PixelFormat::Rgb24, 100, 75);
+}
@@ -0,0 +1,351 @@
+use crate::frame::{Frame, PixelFormat};
+
+/// Weights for [`fuse_exposures`].
+///
+/// Each is an exponent applied to one measure of how well a pixel is exposed. Zero ignores
+/// that measure.
+#[derive(Clone, Copy, Debug)]
+pub struct FusionSettings {
+    /// Favour pixels with detail around them.
+    pub contrast: f32,
+    /// Favour pixels with strong colours.
+    pub saturation: f32,
+    /// Favour pixels which are close to mid grey.
+    pub exposedness: f32,
+}
+
+impl Default for FusionSettings {
+    fn default() -> Self {
+        FusionSettings {
+            contrast: 1.0,
+            saturation: 1.0,
+            exposedness: 1.0,
+        }
+    }
+}
+
+/// Spread of the exposedness curve around mid grey.
+const EXPOSEDNESS_SIGMA: f32 = 0.2;
+
+/// The smallest pyramid level is at least this many pixels across.
+const MIN_LEVEL_SIZE: u32 = 8;
+
+/// Merges bracketed exposures of the same scene into one image using Mertens exposure fusion.
+///
+/// Each pixel of the result is a blend of the input pixels, weighted by how well exposed they
+/// are. The blending is done at several scales so there are no seams between areas taken from
+/// different exposures. No HDR radiance map is produced so the exposure times aren't needed.
+///
+/// The frames must all be RGB24 and the same size. Returns `None` if they aren't or if there
+/// are no frames.
+///
+/// ```
+/// # use rascam::{fuse_exposures, Frame, FusionSettings, PixelFormat};
+/// let dark = Frame::packed(vec![20; 4 * 4 * 3], PixelFormat::Rgb24, 4, 4);
+/// let bright = Frame::packed(vec![230; 4 * 4 * 3], PixelFormat::Rgb24, 4, 4);
+///
+/// let fused = fuse_exposures(&[dark, bright], FusionSettings::default()).unwrap();
+/// assert_eq!(fused.width(), 4);
+/// ```
+pub fn fuse_exposures(frames: &[Frame], settings: FusionSettings) -> Option<Frame> {
+    let first = frames.first()?;
+    let (width, height) = (first.width(), first.height());
+    if frames
+        .iter()
+        .any(|f| f.format() != PixelFormat::Rgb24 || f.width() != width || f.height() != height)
+    {
+        return None;
+    }
+
+    let levels = pyramid_levels(width, height);
+    let images: Vec<[Plane; 3]> = frames.iter().map(channels).collect();
+
+    let mut weights: Vec<Plane> = images.iter().map(|rgb| weight(rgb, settings)).collect();
+    normalize(&mut weights);
+
+    // Blend the detail at each scale using the weights at that scale
+    let mut blended: Vec<[Plane; 3]> = Vec::with_capacity(levels);
+    for (rgb, weight) in images.iter().zip(weights.iter()) {
+        let weights = gaussian_pyramid(weight, levels);
+        for (c, channel) in rgb.iter().enumerate() {
+            let detail = laplacian_pyramid(channel, levels);
+            for (level, (detail, weight)) in detail.iter().zip(weights.iter()).enumerate() {
+                if blended.len() <= level {
+                    let empty = Plane::new(detail.width, detail.height);
+                    blended.push([empty.clone(), empty.clone(), empty]);
+                }
+                let out = &mut blended[level][c];
+                for ((out, d), w) in out.data.iter_mut().zip(&detail.data).zip(&weight.data) {
+                    *out += d * w;
+                }
+            }
+        }
+    }
+
+    let channels: Vec<Plane> = (0..3)
+        .map(|c| {
+            let levels: Vec<&Plane> = blended.iter().map(|level| &level[c]).collect();
+            collapse(&levels)
+        })
+        .collect();
+
+    let mut data = Vec::with_capacity((width * height * 3) as usize);
+    for i in 0..(width * height) as usize {
+        for channel in channels.iter() {
+            data.push((channel.data[i] * 255.0).round().max(0.0).min(255.0) as u8);
+        }
+    }
+
+    Some(Frame::packed(data, PixelFormat::Rgb24, width, height))
+}
+
+#[derive(Clone)]
+struct Plane {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn new(width: u32, height: u32) -> Plane {
+        Plane {
+            width,
+            height,
+            data: vec![0.0; (width * height) as usize],
+        }
+    }
+
+    fn get(&self, x: i64, y: i64) -> f32 {
+        let x = x.max(0).min(self.width as i64 - 1) as u32;
+        let y = y.max(0).min(self.height as i64 - 1) as u32;
+        self.data[(y * self.width + x) as usize]
+    }
+}
+
+fn pyramid_levels(width: u32, height: u32) -> usize {
+    let mut levels = 1;
+    let mut size = width.min(height);
+    while size / 2 >= MIN_LEVEL_SIZE {
+        size = (size + 1) / 2;
+        levels += 1;
+    }
+    levels
+}
+
+/// Splits a frame into red, green and blue planes with values from 0 to 1.
+fn channels(frame: &Frame) -> [Plane; 3] {
+    let (data, stride) = frame.plane(0);
+    let (width, height) = (frame.width(), frame.height());
+    let mut planes = [
+        Plane::new(width, height),
+        Plane::new(width, height),
+        Plane::new(width, height),
+    ];
+
+    for (y, row) in data.chunks(stride).take(height as usize).enumerate() {
+        for (x, pixel) in row.chunks_exact(3).take(width as usize).enumerate() {
+            let i = y * width as usize + x;
+            for (plane, &value) in planes.iter_mut().zip(pixel) {
+                plane.data[i] = value as f32 / 255.0;
+            }
+        }
+    }
+
+    planes
+}
+
+/// How well exposed each pixel is.
+fn weight(rgb: &[Plane; 3], settings: FusionSettings) -> Plane {
+    let (width, height) = (rgb[0].width, rgb[0].height);
+    let mut grey = Plane::new(width, height);
+    for (i, grey) in grey.data.iter_mut().enumerate() {
+        *grey = (rgb[0].data[i] + rgb[1].data[i] + rgb[2].data[i]) / 3.0;
+    }
+
+    let mut weight = Plane::new(width, height);
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            let i = (y as u32 * width + x as u32) as usize;
+            let pixel = [rgb[0].data[i], rgb[1].data[i], rgb[2].data[i]];
+
+            let laplacian =
+                grey.get(x - 1, y) + grey.get(x + 1, y) + grey.get(x, y - 1) + grey.get(x, y + 1)
+                    - 4.0 * grey.get(x, y);
+            let contrast = laplacian.abs();
+
+            let mean = grey.data[i];
+            let saturation =
+                (pixel.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / 3.0).sqrt();
+
+            let exposedness = pixel
+                .iter()
+                .map(|v| {
+                    (-(v - 0.5) * (v - 0.5) / (2.0 * EXPOSEDNESS_SIGMA * EXPOSEDNESS_SIGMA)).exp()
+                })
+                .product::<f32>();
+
+            weight.data[i] = contrast.powf(settings.contrast)
+                * saturation.powf(settings.saturation)
+                * exposedness.powf(settings.exposedness)
+                + 1e-12;
+        }
+    }
+
+    weight
+}
+
+/// Scales the weights so that they sum to one at each pixel.
+fn normalize(weights: &mut [Plane]) {
+    for i in 0..weights[0].data.len() {
+        let sum: f32 = weights.iter().map(|w| w.data[i]).sum();
+        for weight in weights.iter_mut() {
+            weight.data[i] /= sum;
+        }
+    }
+}
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+/// Blurs and halves the size of a plane.
+fn reduce(plane: &Plane) -> Plane {
+    let width = (plane.width + 1) / 2;
+    let height = (plane.height + 1) / 2;
+
+    // Rows first, keeping every other column
+    let mut rows = Plane::new(width, plane.height);
+    for y in 0..plane.height {
+        for x in 0..width {
+            rows.data[(y * width + x) as usize] = KERNEL
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * plane.get(2 * x as i64 + k as i64 - 2, y as i64))
+                .sum();
+        }
+    }
+
+    let mut out = Plane::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            out.data[(y * width + x) as usize] = KERNEL
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * rows.get(x as i64, 2 * y as i64 + k as i64 - 2))
+                .sum();
+        }
+    }
+    out
+}
+
+/// Doubles the size of a plane, cropping it to `width` x `height`.
+fn expand(plane: &Plane, width: u32, height: u32) -> Plane {
+    // Each output pixel is made of the input pixels that line up with the even taps
+    let sample = |x: i64, get: &dyn Fn(i64) -> f32| -> f32 {
+        KERNEL
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| (x - *k as i64 + 2) % 2 == 0)
+            .map(|(k, w)| 2.0 * w * get((x - k as i64 + 2) / 2))
+            .sum()
+    };
+
+    let mut rows = Plane::new(width, plane.height);
+    for y in 0..plane.height {
+        for x in 0..width {
+            rows.data[(y * width + x) as usize] = sample(x as i64, &|sx| plane.get(sx, y as i64));
+        }
+    }
+
+    let mut out = Plane::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            out.data[(y * width + x) as usize] = sample(y as i64, &|sy| rows.get(x as i64, sy));
+        }
+    }
+    out
+}
+
+fn gaussian_pyramid(plane: &Plane, levels: usize) -> Vec<Plane> {
+    let mut pyramid = vec![plane.clone()];
+    for _ in 1..levels {
+        let next = reduce(pyramid.last().unwrap());
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+/// The detail at each scale, with the smallest level holding what remains.
+fn laplacian_pyramid(plane: &Plane, levels: usize) -> Vec<Plane> {
+    let mut gaussian = gaussian_pyramid(plane, levels);
+    for level in 0..levels - 1 {
+        let (width, height) = (gaussian[level].width, gaussian[level].height);
+        let expanded = expand(&gaussian[level + 1], width, height);
+        for (value, e) in gaussian[level].data.iter_mut().zip(expanded.data) {
+            *value -= e;
+        }
+    }
+    gaussian
+}
+
+/// Rebuilds a plane from a laplacian pyramid.
+fn collapse(levels: &[&Plane]) -> Plane {
+    let mut plane = levels[levels.len() - 1].clone();
+    for level in levels.iter().rev().skip(1) {
+        let mut expanded = expand(&plane, level.width, level.height);
+        for (value, detail) in expanded.data.iter_mut().zip(&level.data) {
+            *value += detail;
+        }
+        plane = expanded;
+    }
+    plane
+}
+
+/// A textured frame with values from `low` to `high`.
+#[cfg(test)]
+fn textured(width: u32, height: u32, low: u8, high: u8) -> Frame {
+    let data = (0..width * height * 3)
+        .map(|i| {
+            let pixel = i / 3;
+            let (x, y) = (pixel % width, pixel / width);
+            let t = (x * 7 + y * 13 + i % 3 * 5) % 17;
+            low + (t * (high - low) as u32 / 16) as u8
+        })
+        .collect();
+    Frame::packed(data, PixelFormat::Rgb24, width, height)
+}
+
+#[cfg(test)]
+fn mean(frame: &Frame) -> f32 {
+    frame.data().iter().map(|&v| v as f32).sum::<f32>() / frame.data().len() as f32
+}
+
+#[test]
+fn test_fusion_single_frame() {
+    // A single frame is rebuilt exactly from its pyramid
+    let frame = textured(37, 29, 10, 240);
+    let fused = fuse_exposures(std::slice::from_ref(&frame), FusionSettings::default()).unwrap();
+    for (a, b) in fused.data().iter().zip(frame.data()) {
+        assert!((*a as i32 - *b as i32).abs() <= 1, "{} != {}", a, b);
+    }
+}
+
+#[test]
+fn test_fusion_prefers_well_exposed() {
+    let dark = textured(64, 48, 0, 20);
+    let good = textured(64, 48, 100, 160);
+    let bright = textured(64, 48, 235, 255);
+
+    let fused = fuse_exposures(&[dark, good.clone(), bright], FusionSettings::default()).unwrap();
+    assert_eq!((fused.width(), fused.height()), (64, 48));
+    assert!((mean(&fused) - mean(&good)).abs() < 15.0);
+}
+
+#[test]
+fn test_fusion_invalid_frames() {
+    assert!(fuse_exposures(&[], FusionSettings::default()).is_none());
+
+    let frames = [textured(8, 8, 0, 255), textured(8, 9, 0, 255)];
+    assert!(fuse_exposures(&frames, FusionSettings::default()).is_none());
+
+    let yuv = Frame::packed(vec![0; 8 * 8 * 3 / 2], PixelFormat::I420, 8, 8);
+    assert!(fuse_exposures(&[yuv], FusionSettings::default()).is_none());
+}
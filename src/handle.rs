@@ -0,0 +1,407 @@
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::error::{CameraError, ErrorKind, Stage};
+use crate::info::CameraInfo;
+use crate::metadata::{CaptureMetadata, ControlState, SensorSettings};
+use crate::settings::{CameraSettings, EncoderSettings, SplitterOutput, VideoSettings};
+use crate::stream::VideoStream;
+use crate::SimpleCamera;
+
+/// How long a recording waits for the encoder before checking for commands.
+const RECORDING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where a recording is written to.
+pub type RecordingSink = Box<dyn Write + Send>;
+
+type Reply<T> = oneshot::Sender<Result<T, CameraError>>;
+
+enum Command {
+    TakeOne(Reply<Vec<u8>>),
+    TakeOneWithMetadata(Reply<(Vec<u8>, CaptureMetadata)>),
+    Configure(CameraSettings, Reply<()>),
+    WaitUntilSettled(Duration, Reply<SensorSettings>),
+    StartRecording {
+        video: VideoSettings,
+        encoder: EncoderSettings,
+        sink: RecordingSink,
+        reply: Reply<()>,
+    },
+    StopRecording(Reply<RecordingSink>),
+    State(Reply<CameraState>),
+}
+
+/// What a camera owned by a [`CameraHandle`] is doing.
+#[derive(Clone, Debug)]
+pub struct CameraState {
+    /// The settings the camera was activated with.
+    pub settings: CameraSettings,
+    /// Whether video is being recorded. Stills can't be taken until recording stops.
+    pub recording: bool,
+    /// The exposure and white balance the camera most recently reported.
+    pub sensor: Option<SensorSettings>,
+}
+
+/// A camera running on its own thread, which can be shared by cloning the handle.
+///
+/// Requests from every handle are queued and carried out one at a time. Each request has a
+/// blocking method and an `_async` method which returns once the camera thread has replied.
+/// The camera is dropped once every handle has been dropped.
+///
+/// If the camera thread has stopped, requests fail with [`ErrorKind::Recv`].
+///
+/// ```
+/// use rascam::{CameraHandle, CameraSettings};
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let info = rascam::info().unwrap();
+/// let handle = CameraHandle::spawn(info.cameras[0].clone(), CameraSettings::default()).unwrap();
+/// handle.wait_until_settled(Duration::from_secs(5)).unwrap();
+///
+/// let workers: Vec<_> = (0..4)
+///     .map(|_| {
+///         let handle = handle.clone();
+///         thread::spawn(move || handle.take_one().unwrap())
+///     })
+///     .collect();
+/// for worker in workers {
+///     assert!(!worker.join().unwrap().is_empty());
+/// }
+/// ```
+#[derive(Clone)]
+pub struct CameraHandle {
+    // `mpsc::Sender` is not `Sync` before Rust 1.72, so every clone shares one behind a lock
+    commands: Arc<Mutex<mpsc::Sender<Command>>>,
+}
+
+impl CameraHandle {
+    /// Activates the camera with `settings` and starts a thread to own it.
+    pub fn spawn(info: CameraInfo, settings: CameraSettings) -> Result<CameraHandle, CameraError> {
+        let camera = activate(info.clone(), settings)?;
+        let (commands, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("rascam".to_owned())
+            .spawn(move || run(info, camera, receiver))?;
+        Ok(CameraHandle {
+            commands: Arc::new(Mutex::new(commands)),
+        })
+    }
+
+    /// Captures a single image. See [`SimpleCamera::take_one`].
+    pub fn take_one(&self) -> Result<Vec<u8>, CameraError> {
+        block_on(self.request(Command::TakeOne))
+    }
+
+    /// Captures a single image asynchronously. See [`SimpleCamera::take_one`].
+    pub async fn take_one_async(&self) -> Result<Vec<u8>, CameraError> {
+        self.request(Command::TakeOne).await
+    }
+
+    /// Captures a single image along with the camera's settings at the time.
+    ///
+    /// See [`SimpleCamera::take_one_with_metadata`].
+    pub fn take_one_with_metadata(&self) -> Result<(Vec<u8>, CaptureMetadata), CameraError> {
+        block_on(self.request(Command::TakeOneWithMetadata))
+    }
+
+    /// Captures a single image along with the camera's settings at the time, asynchronously.
+    ///
+    /// See [`SimpleCamera::take_one_with_metadata`].
+    pub async fn take_one_with_metadata_async(
+        &self,
+    ) -> Result<(Vec<u8>, CaptureMetadata), CameraError> {
+        self.request(Command::TakeOneWithMetadata).await
+    }
+
+    /// Replaces the camera with one activated with `settings`.
+    ///
+    /// If activating the new camera fails then later requests fail with
+    /// [`ErrorKind::InvalidState`] until the camera is configured successfully.
+    pub fn configure(&self, settings: CameraSettings) -> Result<(), CameraError> {
+        block_on(self.request(|reply| Command::Configure(settings, reply)))
+    }
+
+    /// Replaces the camera with one activated with `settings`, asynchronously.
+    ///
+    /// See [`configure`](CameraHandle::configure).
+    pub async fn configure_async(&self, settings: CameraSettings) -> Result<(), CameraError> {
+        self.request(|reply| Command::Configure(settings, reply))
+            .await
+    }
+
+    /// Waits for the exposure and white balance to settle. See
+    /// [`SimpleCamera::wait_until_settled`].
+    pub fn wait_until_settled(&self, timeout: Duration) -> Result<SensorSettings, CameraError> {
+        block_on(self.request(|reply| Command::WaitUntilSettled(timeout, reply)))
+    }
+
+    /// Waits for the exposure and white balance to settle, asynchronously. See
+    /// [`SimpleCamera::wait_until_settled`].
+    pub async fn wait_until_settled_async(
+        &self,
+        timeout: Duration,
+    ) -> Result<SensorSettings, CameraError> {
+        self.request(|reply| Command::WaitUntilSettled(timeout, reply))
+            .await
+    }
+
+    /// Starts encoding video from the video port and writing it to `sink`.
+    ///
+    /// Recording continues in the background until [`stop_recording`] is called. Requests for
+    /// stills fail with [`ErrorKind::InvalidState`] in the meantime.
+    ///
+    /// [`stop_recording`]: CameraHandle::stop_recording
+    pub fn start_recording(
+        &self,
+        video: VideoSettings,
+        encoder: EncoderSettings,
+        sink: RecordingSink,
+    ) -> Result<(), CameraError> {
+        block_on(self.request(|reply| Command::StartRecording {
+            video,
+            encoder,
+            sink,
+            reply,
+        }))
+    }
+
+    /// Starts recording asynchronously. See [`start_recording`].
+    ///
+    /// [`start_recording`]: CameraHandle::start_recording
+    pub async fn start_recording_async(
+        &self,
+        video: VideoSettings,
+        encoder: EncoderSettings,
+        sink: RecordingSink,
+    ) -> Result<(), CameraError> {
+        self.request(|reply| Command::StartRecording {
+            video,
+            encoder,
+            sink,
+            reply,
+        })
+        .await
+    }
+
+    /// Stops recording and returns the sink the video was written to.
+    ///
+    /// If recording had already stopped because of an error, that error is returned.
+    pub fn stop_recording(&self) -> Result<RecordingSink, CameraError> {
+        block_on(self.request(Command::StopRecording))
+    }
+
+    /// Stops recording asynchronously. See [`stop_recording`].
+    ///
+    /// [`stop_recording`]: CameraHandle::stop_recording
+    pub async fn stop_recording_async(&self) -> Result<RecordingSink, CameraError> {
+        self.request(Command::StopRecording).await
+    }
+
+    /// Returns what the camera is doing.
+    pub fn state(&self) -> Result<CameraState, CameraError> {
+        block_on(self.request(Command::State))
+    }
+
+    /// Returns what the camera is doing, asynchronously.
+    pub async fn state_async(&self) -> Result<CameraState, CameraError> {
+        self.request(Command::State).await
+    }
+
+    /// Queues a command straight away and returns its reply.
+    fn request<T>(
+        &self,
+        command: impl FnOnce(Reply<T>) -> Command,
+    ) -> impl Future<Output = Result<T, CameraError>> {
+        let (reply, receiver) = oneshot::channel();
+        let sent = self.commands.lock().send(command(reply));
+        async move {
+            // Either way the camera thread has gone
+            sent.map_err(|_| mpsc::RecvError)?;
+            receiver.await.map_err(|_| mpsc::RecvError)?
+        }
+    }
+}
+
+fn activate(info: CameraInfo, settings: CameraSettings) -> Result<SimpleCamera, CameraError> {
+    let mut camera = SimpleCamera::new(info)?;
+    camera.configure(settings);
+    camera.activate()?;
+    Ok(camera)
+}
+
+/// Carries out commands until every handle has been dropped.
+fn run(info: CameraInfo, camera: SimpleCamera, commands: mpsc::Receiver<Command>) {
+    let mut camera = Some(camera);
+    // An error that stopped a recording, reported when the recording is stopped
+    let mut recording_error = None;
+
+    while let Ok(command) = commands.recv() {
+        match command {
+            Command::Configure(settings, reply) => {
+                // The old camera has to be dropped to free the hardware
+                camera = None;
+                let result = activate(info.clone(), settings).map(|new| camera = Some(new));
+                let _ = reply.send(result);
+            }
+            Command::StopRecording(reply) => {
+                let result = Err(recording_error
+                    .take()
+                    .unwrap_or_else(|| ErrorKind::InvalidState(Stage::Encoder).into()));
+                let _ = reply.send(result);
+            }
+            command => match camera.as_mut() {
+                Some(camera) => {
+                    if let Some(err) = handle(camera, command, &commands) {
+                        recording_error = Some(err);
+                    }
+                }
+                None => reject(command, ErrorKind::InvalidState(Stage::Camera)),
+            },
+        }
+    }
+    debug!("camera thread finished");
+}
+
+/// Carries out a command which needs the camera, returning an error that stopped a recording.
+fn handle(
+    camera: &mut SimpleCamera,
+    command: Command,
+    commands: &mpsc::Receiver<Command>,
+) -> Option<CameraError> {
+    match command {
+        Command::TakeOne(reply) => {
+            let _ = reply.send(camera.take_one());
+        }
+        Command::TakeOneWithMetadata(reply) => {
+            let _ = reply.send(camera.take_one_with_metadata());
+        }
+        Command::WaitUntilSettled(timeout, reply) => {
+            let _ = reply.send(camera.wait_until_settled(timeout));
+        }
+        Command::State(reply) => {
+            let _ = reply.send(Ok(CameraState {
+                settings: camera.settings.clone().unwrap_or_default(),
+                recording: false,
+                sensor: camera.serious.sensor_settings(),
+            }));
+        }
+        Command::StartRecording {
+            video,
+            encoder,
+            sink,
+            reply,
+        } => {
+            // The stream borrows the camera, so keep what is needed to report its state
+            let settings = camera.settings.clone().unwrap_or_default();
+            let control = Arc::clone(&camera.serious.control);
+
+            let outputs = [SplitterOutput::Encoder(encoder)];
+            let stream = camera
+                .split(video, &outputs)
+                .map(|mut streams| streams.pop().and_then(|stream| stream.into_video()));
+            match stream {
+                Ok(Some(stream)) => {
+                    let _ = reply.send(Ok(()));
+                    return record(stream, sink, commands, settings, &control);
+                }
+                Ok(None) => unreachable!("the splitter only has an encoder output"),
+                Err(err) => {
+                    let _ = reply.send(Err(err));
+                }
+            }
+        }
+        Command::Configure(..) | Command::StopRecording(_) => unreachable!(),
+    }
+    None
+}
+
+/// Writes video to `sink` until recording is stopped, answering commands in the meantime.
+///
+/// Returns the error if recording stopped because of one.
+fn record(
+    mut stream: VideoStream,
+    mut sink: RecordingSink,
+    commands: &mpsc::Receiver<Command>,
+    settings: CameraSettings,
+    control: &ControlState,
+) -> Option<CameraError> {
+    stream.set_timeout(Some(RECORDING_POLL_INTERVAL));
+
+    loop {
+        loop {
+            match commands.try_recv() {
+                Ok(Command::StopRecording(reply)) => {
+                    let _ = reply.send(Ok(sink));
+                    return None;
+                }
+                Ok(Command::State(reply)) => {
+                    let _ = reply.send(Ok(CameraState {
+                        settings: settings.clone(),
+                        recording: true,
+                        sensor: *control.sensor.lock(),
+                    }));
+                }
+                Ok(command) => reject(command, ErrorKind::InvalidState(Stage::Encoder)),
+                Err(mpsc::TryRecvError::Empty) => break,
+                // Every handle has gone
+                Err(mpsc::TryRecvError::Disconnected) => return None,
+            }
+        }
+
+        let result = match stream.next_buffer() {
+            Ok(buffer) => sink.write_all(buffer.data()).map_err(CameraError::from),
+            Err(err) => match err.kind() {
+                ErrorKind::Timeout(_) => Ok(()),
+                _ => Err(err),
+            },
+        };
+        if let Err(err) = result {
+            debug!("recording stopped: {}", err);
+            return Some(err);
+        }
+    }
+}
+
+/// Answers a command with an error without carrying it out.
+fn reject(command: Command, kind: ErrorKind) {
+    // Nobody may be waiting for the reply, which is fine
+    match command {
+        Command::TakeOne(reply) => drop(reply.send(Err(kind.into()))),
+        Command::TakeOneWithMetadata(reply) => drop(reply.send(Err(kind.into()))),
+        Command::Configure(_, reply) => drop(reply.send(Err(kind.into()))),
+        Command::WaitUntilSettled(_, reply) => drop(reply.send(Err(kind.into()))),
+        Command::StartRecording { reply, .. } => drop(reply.send(Err(kind.into()))),
+        Command::StopRecording(reply) => drop(reply.send(Err(kind.into()))),
+        Command::State(reply) => drop(reply.send(Err(kind.into()))),
+    }
+}
+
+#[test]
+fn test_camera_handle_without_thread() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CameraHandle>();
+
+    let (commands, receiver) = mpsc::channel();
+    let handle = CameraHandle {
+        commands: Arc::new(Mutex::new(commands)),
+    };
+    drop(receiver);
+
+    match handle.take_one().unwrap_err().kind() {
+        ErrorKind::Recv(_) => {}
+        kind => panic!("unexpected error {:?}", kind),
+    }
+    match block_on(handle.state_async()).unwrap_err().kind() {
+        ErrorKind::Recv(_) => {}
+        kind => panic!("unexpected error {:?}", kind),
+    }
+}
@@ -6,7 +6,7 @@ use std::mem;
 use std::os::raw::c_char;
 use std::string::String;
 
-use crate::error::{CameraError, MmalError};
+use crate::error::{CameraError, MmalError, Stage};
 use crate::init::init;
 
 /// Contains information about attached cameras.
@@ -97,13 +97,16 @@ pub fn info() -> Result<Info, CameraError> {
                         ffi::mmal_component_destroy(component);
                         Err(
                             MmalError::with_status("Failed to get camera info".to_owned(), s)
+                                .with_stage(Stage::Camera)
                                 .into(),
                         )
                     }
                 }
             }
             s => Err(
-                MmalError::with_status("Failed to create camera component".to_owned(), s).into(),
+                MmalError::with_status("Failed to create camera component".to_owned(), s)
+                    .with_stage(Stage::Camera)
+                    .into(),
             ),
         }
     }
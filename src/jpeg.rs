@@ -0,0 +1,48 @@
+/// Start of image marker
+const SOI: [u8; 2] = [0xff, 0xd8];
+/// End of image marker
+const EOI: [u8; 2] = [0xff, 0xd9];
+
+/// Checks that a JPEG arriving in pieces starts and ends with the right markers, which
+/// catches most truncated images.
+#[derive(Default)]
+pub(crate) struct JpegMarkers {
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl JpegMarkers {
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        let needed = SOI.len().saturating_sub(self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..needed]);
+
+        self.tail
+            .extend_from_slice(&data[data.len().saturating_sub(EOI.len())..]);
+        let excess = self.tail.len().saturating_sub(EOI.len());
+        self.tail.drain(..excess);
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.head == SOI && self.tail == EOI
+    }
+}
+
+#[test]
+fn test_jpeg_markers() {
+    let mut markers = JpegMarkers::default();
+    assert!(!markers.is_valid());
+
+    // Markers split across pieces
+    for piece in [&[0xff][..], &[0xd8, 1, 2, 3], &[], &[4, 0xff], &[0xd9]].iter() {
+        markers.push(piece);
+    }
+    assert!(markers.is_valid());
+
+    let mut truncated = JpegMarkers::default();
+    truncated.push(&[0xff, 0xd8, 1, 2, 3]);
+    assert!(!truncated.is_valid());
+
+    let mut headless = JpegMarkers::default();
+    headless.push(&[0, 0xd8, 0xff, 0xd9]);
+    assert!(!headless.is_valid());
+}
@@ -5,47 +5,105 @@
 //!
 //! [mmal-sys]: https://crates.io/crates/mmal-sys
 
-use mmal_sys as ffi;
-#[macro_use(defer_on_unwind)]
-extern crate scopeguard;
 use ffi::MMAL_STATUS_T;
-use futures::future::FutureExt;
+use futures::future::{self, Either};
 use futures::stream::StreamExt;
-use parking_lot::{lock_api::RawMutex, Mutex};
+use mmal_sys as ffi;
+use parking_lot::Mutex;
+use std::cmp;
+use std::collections::VecDeque;
 use std::ffi::CStr;
 use std::io::Write;
 use std::mem;
 use std::mem::MaybeUninit;
 use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 use std::ptr::NonNull;
 use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+mod convert;
 mod error;
+mod frame;
+mod fusion;
+mod handle;
 mod info;
 mod init;
+mod jpeg;
+mod metadata;
+pub mod motion;
+mod pipeline;
+mod port;
+mod queue;
+mod recorder;
 mod settings;
-
-pub use error::{CameraError, MmalError};
+mod stream;
+mod vectors;
+
+pub use convert::{convert, ColorMatrix, ColorRange, ColorSpace};
+pub use error::{CameraError, ErrorKind, MmalError, MmalStatus, Stage};
+pub use frame::{Frame, PixelFormat};
+pub use fusion::{fuse_exposures, FusionSettings};
+pub use handle::{CameraHandle, CameraState, RecordingSink};
 pub use info::*;
 use init::init;
+use jpeg::JpegMarkers;
+use metadata::{to_rational, ControlState, SettleTracker};
+pub use metadata::{CameraEvent, CaptureMetadata, SensorSettings};
+use pipeline::{Component, Connection, Pipeline};
+use port::PortSink;
+pub use recorder::{CircularRecorder, RecorderLimit};
 pub use settings::*;
+pub use stream::{EncodedBuffer, FrameStream, SplitterStream, VideoStream};
+pub use vectors::{MotionVector, MotionVectors};
 
 const MMAL_CAMERA_PREVIEW_PORT: isize = 0;
 const MMAL_CAMERA_VIDEO_PORT: isize = 1;
 const MMAL_CAMERA_CAPTURE_PORT: isize = 2;
 
+/// How long to wait for the exposure to settle before taking each image of a bracket.
+const BRACKET_SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How far the reported exposure can be from a requested shutter speed, as a fraction of it.
+const SHUTTER_TOLERANCE: f32 = 0.1;
+
+/// The video splitter has four output ports.
+const MAX_SPLITTER_OUTPUTS: usize = 4;
+
 /// Video render needs at least 2 buffers.
 const VIDEO_OUTPUT_BUFFERS_NUM: u32 = 3;
 
+/// How long to wait for a still image before giving up, unless changed with
+/// [`SimpleCamera::set_capture_timeout`].
+const DEFAULT_CAPTURE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a [`delay`] checks whether it is still wanted.
+const DELAY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Buffer flags which mean that the image is unusable.
+const CAPTURE_FAILED_FLAGS: u32 =
+    ffi::MMAL_BUFFER_HEADER_FLAG_TRANSMISSION_FAILED | ffi::MMAL_BUFFER_HEADER_FLAG_CORRUPTED;
+
+/// How many still captures can be in progress at once unless changed with
+/// [`SeriousCamera::set_capture_queue_depth`].
+const DEFAULT_CAPTURE_QUEUE_DEPTH: usize = 2;
+
+/// MMAL's marker for a missing timestamp.
+const MMAL_TIME_UNKNOWN: i64 = std::i64::MIN;
+
 const PREVIEW_FRAME_RATE_NUM: i32 = 0;
 const PREVIEW_FRAME_RATE_DEN: i32 = 1;
 
 // TODO: what about the rest of these formats?
 pub use ffi::MMAL_ENCODING_GIF;
+pub use ffi::MMAL_ENCODING_H264;
 pub use ffi::MMAL_ENCODING_JPEG;
+pub use ffi::MMAL_ENCODING_MJPEG;
 pub use ffi::MMAL_ENCODING_PNG;
 
 pub use ffi::MMAL_ENCODING_OPAQUE;
@@ -54,12 +112,88 @@ pub use ffi::MMAL_ENCODING_RGB24;
 use tracing::debug;
 
 struct Userdata {
-    pool: NonNull<ffi::MMAL_POOL_T>,
-    _guard: Arc<Mutex<()>>,
-    sender: SenderKind,
+    pool: Arc<StillPool>,
+    /// Captures waiting for the camera, oldest first. The first one receives the buffers.
+    captures: Mutex<VecDeque<QueuedCapture>>,
+    /// The camera's still port, for arming the next capture.
+    still_port: *mut ffi::MMAL_PORT_T,
+}
+
+/// A capture requested with [`SeriousCamera::take`] or [`SeriousCamera::take_async`].
+struct QueuedCapture {
+    /// `None` once the capture has failed or been cancelled. Its receiver is then disconnected
+    /// and the rest of the image is handed back to the camera.
+    sender: Option<SenderKind>,
+    ticket: Arc<CaptureTicket>,
+    /// Identifies the capture so that it can be cancelled on its own.
+    id: u64,
+}
+
+impl QueuedCapture {
+    /// Sends a buffer of the image to the capture's receiver.
+    ///
+    /// If the receiver has been dropped, or its channel is full, the capture fails. Dropping the
+    /// sender disconnects the receiver, which is how it learns that the image is incomplete.
+    /// A buffer that isn't delivered is returned rather than dropped, because dropping it hands
+    /// it back to the port, which must wait until the queue is unlocked.
+    fn deliver(&mut self, guard: BufferGuard) -> Delivery {
+        let (cancelled, guard) = match &mut self.sender {
+            Some(SenderKind::AsyncSender(sender)) => match sender.try_send(guard) {
+                Ok(()) => return Delivery::Delivered,
+                Err(e) => (e.is_disconnected(), Some(e.into_inner())),
+            },
+            Some(SenderKind::SyncSender(sender)) => match sender.try_send(Some(guard)) {
+                Ok(()) => return Delivery::Delivered,
+                Err(mpsc::TrySendError::Disconnected(guard)) => (true, guard),
+                Err(mpsc::TrySendError::Full(guard)) => (false, guard),
+            },
+            // Already failed, so the buffer goes straight back
+            None => return Delivery::Discarded(guard),
+        };
+
+        if cancelled {
+            debug!("Capture cancelled, discarding the rest of the image");
+        } else {
+            debug!("Unable to deliver a buffer, failing the capture");
+        }
+
+        self.sender = None;
+        Delivery::Failed(guard)
+    }
+}
+
+/// What became of a buffer handed to [`QueuedCapture::deliver`].
+enum Delivery {
+    Delivered,
+    /// The capture had already failed.
+    Discarded(BufferGuard),
+    /// The capture failed just now, and should be disarmed unless the image is complete.
+    Failed(Option<BufferGuard>),
+}
+
+/// Counts a capture as in progress until it has finished and every buffer of it has been
+/// released.
+#[derive(Debug)]
+struct CaptureTicket {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl CaptureTicket {
+    fn new(in_flight: &Arc<AtomicUsize>) -> Arc<CaptureTicket> {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        Arc::new(CaptureTicket {
+            in_flight: Arc::clone(in_flight),
+        })
+    }
 }
 
-pub enum SenderKind {
+impl Drop for CaptureTicket {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) enum SenderKind {
     SyncSender(mpsc::SyncSender<Option<BufferGuard>>),
     AsyncSender(futures::channel::mpsc::Sender<BufferGuard>),
 }
@@ -69,37 +203,161 @@ enum ReceiverKind {
     AsyncReceiver(futures::channel::mpsc::Receiver<BufferGuard>),
 }
 
+/// The pool that still images are captured into.
+///
+/// The pool holds a reference on the component that owns its port, and every [`BufferGuard`]
+/// shares the pool, so the buffers, the port and the component all stay alive until both the
+/// camera and the last guard have been dropped.
+#[derive(Debug)]
+struct StillPool {
+    component: NonNull<ffi::MMAL_COMPONENT_T>,
+    port: *mut ffi::MMAL_PORT_T,
+    pool: NonNull<ffi::MMAL_POOL_T>,
+}
+
+impl StillPool {
+    /// Creates a pool for `port` using the port's buffer count and size.
+    ///
+    /// # Safety
+    ///
+    /// `port` must be an output port of `component`.
+    unsafe fn new(
+        component: NonNull<ffi::MMAL_COMPONENT_T>,
+        port: *mut ffi::MMAL_PORT_T,
+    ) -> Result<StillPool, CameraError> {
+        let pool = ffi::mmal_port_pool_create(port, (*port).buffer_num, (*port).buffer_size);
+        match NonNull::new(pool) {
+            Some(pool) => {
+                // Released when the pool is dropped. Until then MMAL won't destroy the
+                // component, even if the camera asks it to.
+                ffi::mmal_component_acquire(component.as_ptr());
+                Ok(StillPool {
+                    component,
+                    port,
+                    pool,
+                })
+            }
+            None => {
+                debug!(
+                    "Failed to create buffer header pool for camera port {}",
+                    CStr::from_ptr((*port).name).to_string_lossy()
+                );
+                Err(ErrorKind::PoolExhausted(Stage::Pool).into())
+            }
+        }
+    }
+
+    fn queue(&self) -> *mut ffi::MMAL_QUEUE_T {
+        unsafe { self.pool.as_ref().queue }
+    }
+}
+
+// SAFETY: The pointers are set in `new` and never change. The only `&self` access is `queue`,
+// which the camera callback and guards on other threads take buffers from and return them to,
+// and an MMAL queue serialises that with its own lock. `Arc` ensures the pool is destroyed and
+// the component released once, after the camera has disabled the port.
+unsafe impl Send for StillPool {}
+unsafe impl Sync for StillPool {}
+
+impl Drop for StillPool {
+    fn drop(&mut self) {
+        unsafe {
+            // The port has already been disabled by the camera
+            ffi::mmal_port_pool_destroy(self.port, self.pool.as_ptr());
+            debug!("pool destroyed");
+            ffi::mmal_component_release(self.component.as_ptr());
+        }
+    }
+}
+
 /// Guard around a buffer header.
 ///
 /// Releases buffer header when it is dropped.
+///
+/// A guard keeps the buffer it refers to alive, so it is fine to keep one after the camera it
+/// came from has been dropped:
+///
+/// ```no_run
+/// use rascam::{CameraSettings, SeriousCamera};
+///
+/// # fn main() -> Result<(), rascam::CameraError> {
+/// let mut camera = SeriousCamera::new()?;
+/// camera.set_camera_num(0)?;
+/// camera.create_encoder()?;
+/// camera.enable_control_port(false)?;
+/// camera.set_camera_params(&rascam::info()?.cameras[0])?;
+/// camera.set_camera_format(&CameraSettings {
+///     encoding: rascam::MMAL_ENCODING_RGB24,
+///     width: 1024,
+///     height: 768,
+///     use_encoder: false,
+///     ..CameraSettings::default()
+/// })?;
+/// camera.enable()?;
+/// camera.create_pool()?;
+///
+/// let receiver = camera.take()?;
+/// let guard = receiver.recv().unwrap().unwrap();
+/// drop(receiver);
+/// drop(camera);
+///
+/// // The camera's resources are only destroyed when the guard is dropped
+/// println!("{} bytes", guard.get_bytes().len());
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Debug)]
 pub struct BufferGuard {
     port: *mut ffi::MMAL_PORT_T,
     buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
-    pool: NonNull<ffi::MMAL_POOL_T>,
+    pool: Arc<StillPool>,
+    _capture: Arc<CaptureTicket>,
     complete: bool,
+    failed: bool,
 }
 
 impl BufferGuard {
-    pub fn new(
+    /// Only the capture callback creates guards, since a guard must hold the pool and capture
+    /// that its buffer belongs to.
+    fn new(
         port: *mut ffi::MMAL_PORT_T,
         buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
-        pool: NonNull<ffi::MMAL_POOL_T>,
+        pool: Arc<StillPool>,
+        capture: Arc<CaptureTicket>,
         complete: bool,
+        failed: bool,
     ) -> BufferGuard {
         BufferGuard {
             port,
             buffer,
             pool,
-            complete,
+            _capture: capture,
+            complete: complete || failed,
+            failed,
         }
     }
 
     /// Indicates if an image has been captured and this is the end of the image.
+    ///
+    /// This is also the case if the capture failed, see [`is_failed`](BufferGuard::is_failed).
     pub fn is_complete(&self) -> bool {
         self.complete
     }
 
+    /// Indicates if the camera failed to capture the image, the data is corrupt or the stream
+    /// ended before the end of the image. The image should be thrown away.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Presentation timestamp of the buffer in microseconds, if the camera provided one.
+    pub fn pts(&self) -> Option<i64> {
+        match unsafe { (*self.buffer).pts } {
+            MMAL_TIME_UNKNOWN => None,
+            pts => Some(pts),
+        }
+    }
+
     /// Creates a slice representing the raw bytes of the image.
     ///
     /// The data buffer is owned by the camera and must be copied to keep it around after the
@@ -115,6 +373,13 @@ impl BufferGuard {
     }
 }
 
+// SAFETY: The callback gives up the buffer header when it creates the guard, and the header's
+// memory stays locked until the guard is dropped, so MMAL doesn't touch it in the meantime and
+// reading the data or flags through `&self` from several threads is fine. `drop` only uses the
+// shared `StillPool` and sends a buffer to the port, which MMAL locks.
+unsafe impl Send for BufferGuard {}
+unsafe impl Sync for BufferGuard {}
+
 impl Drop for BufferGuard {
     /// Unlocks and releases the buffer header. Gets new buffer from pool and passes it to
     /// the camera.
@@ -129,7 +394,7 @@ impl Drop for BufferGuard {
             if (*self.port).is_enabled > 0 {
                 let mut status = ffi::MMAL_STATUS_T::MMAL_STATUS_MAX;
                 let new_buffer: *mut ffi::MMAL_BUFFER_HEADER_T =
-                    ffi::mmal_queue_get(self.pool.as_ref().queue);
+                    ffi::mmal_queue_get(self.pool.queue());
 
                 if !new_buffer.is_null() {
                     status = ffi::mmal_port_send_buffer(self.port, new_buffer);
@@ -139,25 +404,30 @@ impl Drop for BufferGuard {
                     debug!("Unable to return the buffer to the port");
                 }
             }
-
-            if self.complete {
-                if !(*self.port).userdata.is_null() {
-                    drop_port_userdata(self.port);
-                }
-                debug!("complete");
-            }
         }
     }
 }
 
+/// A camera, controlled through MMAL.
+///
+/// The camera can be moved to and shared between threads. MMAL is thread safe: it locks a
+/// port while changing or reading its parameters and calls back from its own threads whichever
+/// thread set up the camera. The state shared with the callbacks is behind atomics and mutexes.
 #[repr(C)]
 pub struct SeriousCamera {
     camera: NonNull<ffi::MMAL_COMPONENT_T>,
     enabled: bool,
     camera_port_enabled: bool,
     still_port_enabled: bool,
-    pool: Option<NonNull<ffi::MMAL_POOL_T>>,
-    mutex: Arc<Mutex<()>>,
+    pool: Option<Arc<StillPool>>,
+    control: Arc<ControlState>,
+    /// Number of still captures that haven't finished or whose buffers are still held.
+    in_flight: Arc<AtomicUsize>,
+    capture_queue_depth: usize,
+    /// The id of the next capture to be queued.
+    next_capture_id: u64,
+    /// Capacity of the channel each capture is received from. `None` has room for every buffer.
+    channel_depth: Option<usize>,
 
     encoder: Option<NonNull<ffi::MMAL_COMPONENT_T>>,
     encoder_created: bool,
@@ -172,8 +442,20 @@ pub struct SeriousCamera {
     preview_connection: Option<NonNull<ffi::MMAL_CONNECTION_T>>,
 
     use_encoder: bool,
+    /// Set from [`CameraSettings::still_resizer`], and connected along with the encoder.
+    still_resizer_settings: Option<ResizerSettings>,
+    /// The scaled still images, once the still port has been split between the encoder and
+    /// the resizer.
+    still_resizer: Option<FrameStream<'static>>,
 }
 
+// SAFETY: Enabling, disabling and reconfiguring the camera all take `&mut self`. Through `&self`
+// there are `exposure_compensation` and `shutter_speed`, which get a control port parameter
+// under MMAL's port lock, `sensor_settings` and `events`, which lock `ControlState`, and getters
+// of plain fields.
+unsafe impl Send for SeriousCamera {}
+unsafe impl Sync for SeriousCamera {}
+
 impl SeriousCamera {
     pub fn new() -> Result<SeriousCamera, CameraError> {
         init();
@@ -190,7 +472,11 @@ impl SeriousCamera {
                         enabled: false,
                         camera_port_enabled: false,
                         pool: None,
-                        mutex: Arc::new(Mutex::new(())),
+                        control: Arc::new(ControlState::default()),
+                        in_flight: Arc::new(AtomicUsize::new(0)),
+                        capture_queue_depth: DEFAULT_CAPTURE_QUEUE_DEPTH,
+                        next_capture_id: 0,
+                        channel_depth: None,
                         still_port_enabled: false,
                         // this is really a hack. ideally these objects wouldn't be structured this way
                         encoder_created: false,
@@ -203,9 +489,17 @@ impl SeriousCamera {
                         preview: None,
                         preview_connection: None,
                         use_encoder: false,
+                        still_resizer_settings: None,
+                        still_resizer: None,
                     })
                 }
-                s => Err(MmalError::with_status("Could not create camera".to_owned(), s).into()),
+                // This is what MMAL returns when the camera isn't connected or enabled
+                MMAL_STATUS_T::MMAL_ENOENT => Err(ErrorKind::NoCamera.into()),
+                s => Err(
+                    MmalError::with_status("Could not create camera".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
             }
         }
     }
@@ -220,9 +514,11 @@ impl SeriousCamera {
             let status = ffi::mmal_port_parameter_set(self.camera.as_ref().control, &param.hdr);
             match status {
                 MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
-                s => {
-                    Err(MmalError::with_status("Unable to set camera number".to_owned(), s).into())
-                }
+                s => Err(
+                    MmalError::with_status("Unable to set camera number".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
             }
         }
     }
@@ -240,12 +536,20 @@ impl SeriousCamera {
                     self.encoder_created = true;
                     Ok(())
                 }
-                s => Err(MmalError::with_status("Unable to create encoder".to_owned(), s).into()),
+                s => Err(
+                    MmalError::with_status("Unable to create encoder".to_owned(), s)
+                        .with_stage(Stage::Encoder)
+                        .into(),
+                ),
             }
         }
     }
 
     pub fn connect_encoder(&mut self) -> Result<(), CameraError> {
+        if let Some(resizer) = self.still_resizer_settings.clone() {
+            return unsafe { self.connect_encoder_and_resizer(&resizer) };
+        }
+
         unsafe {
             let mut connection_ptr = MaybeUninit::uninit();
             let status = ffi::mmal_connection_create(
@@ -260,6 +564,7 @@ impl SeriousCamera {
                     "Unable to create camera->encoder connection".to_owned(),
                     status,
                 )
+                .with_stage(Stage::Connection)
                 .into());
             }
 
@@ -273,27 +578,129 @@ impl SeriousCamera {
                     "Unable to enable camera->encoder connection".to_owned(),
                     s,
                 )
+                .with_stage(Stage::Connection)
                 .into()),
             }
             // Ok(())
         }
     }
 
+    /// Splits the still port between the encoder and a resizer, so that every still image is
+    /// also scaled. The scaled images are received with
+    /// [`next_resized_still`](SeriousCamera::next_resized_still).
+    unsafe fn connect_encoder_and_resizer(
+        &mut self,
+        resizer: &ResizerSettings,
+    ) -> Result<(), CameraError> {
+        let still_port = *self.camera.as_ref().output.offset(MMAL_CAMERA_CAPTURE_PORT);
+        let encoder_input = *self.encoder.unwrap().as_ref().input.offset(0);
+        let mut pipeline = Pipeline::default();
+
+        let splitter = Component::new(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_SPLITTER, Stage::Splitter)?;
+        let splitter_input = splitter.input(0);
+        let to_encoder = splitter.output(0);
+        let to_resizer = splitter.output(1);
+        pipeline.add_component(splitter);
+        pipeline.add_connection(Connection::new(still_port, splitter_input)?);
+
+        ffi::mmal_format_copy((*to_encoder).format, (*splitter_input).format);
+        ffi::mmal_format_copy((*to_resizer).format, (*splitter_input).format);
+        // The resizers don't accept opaque frames
+        (*(*to_resizer).format).encoding = ffi::MMAL_ENCODING_I420;
+        for &port in &[to_encoder, to_resizer] {
+            let status = ffi::mmal_port_format_commit(port);
+            if status != MMAL_STATUS_T::MMAL_SUCCESS {
+                return Err(MmalError::with_status(
+                    "Unable to set splitter output format".to_owned(),
+                    status,
+                )
+                .with_stage(Stage::Splitter)
+                .into());
+            }
+        }
+
+        pipeline.add_connection(Connection::new(to_encoder, encoder_input)?);
+        let output = create_resizer(to_resizer, resizer, &mut pipeline)?;
+        pipeline.enable()?;
+
+        let sink = PortSink::new(output, resizer.buffers, Stage::Resizer)?;
+        // Scaled images that are never received mustn't hold up the stills
+        sink.set_backpressure(Backpressure::DropOldest);
+        self.still_resizer = Some(FrameStream::new(
+            sink,
+            Arc::new(pipeline),
+            resizer.format,
+            resizer.width,
+            resizer.height,
+        ));
+        Ok(())
+    }
+
+    /// Waits for the scaled copy of a still image, when [`CameraSettings::still_resizer`] is
+    /// set.
+    ///
+    /// Each still image taken produces one scaled frame. Only the most recent frames are kept,
+    /// so frames that aren't received in time are dropped. Waits forever if `timeout` is
+    /// `None`. Fails with [`ErrorKind::InvalidState`] if the stills aren't being resized.
+    pub fn next_resized_still(&mut self, timeout: Option<Duration>) -> Result<Frame, CameraError> {
+        match &mut self.still_resizer {
+            Some(stream) => {
+                stream.set_timeout(timeout);
+                stream.next_frame()
+            }
+            None => Err(ErrorKind::InvalidState(Stage::Resizer).into()),
+        }
+    }
+
+    /// Drops the scaled still images that are waiting, so that the next one received is from
+    /// the next capture.
+    fn discard_resized_stills(&mut self) {
+        if let Some(stream) = &mut self.still_resizer {
+            stream.discard_waiting();
+        }
+    }
+
     pub fn enable_control_port(&mut self, get_buffers: bool) -> Result<(), CameraError> {
         unsafe {
+            let control = self.camera.as_ref().control;
             let cb: ffi::MMAL_PORT_BH_CB_T = if get_buffers {
                 Some(camera_buffer_callback)
             } else {
+                // Ask the camera to report its exposure and white balance
+                let request = ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T {
+                    hdr: ffi::MMAL_PARAMETER_HEADER_T {
+                        id: ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST,
+                        size: mem::size_of::<ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T>() as u32,
+                    },
+                    change_id: ffi::MMAL_PARAMETER_CAMERA_SETTINGS,
+                    enable: 1,
+                };
+                let status = ffi::mmal_port_parameter_set(control, &request.hdr);
+                if status != MMAL_STATUS_T::MMAL_SUCCESS {
+                    return Err(MmalError::with_status(
+                        "Unable to request camera settings events".to_owned(),
+                        status,
+                    )
+                    .with_stage(Stage::Camera)
+                    .into());
+                }
+
+                if (*control).userdata.is_null() {
+                    (*control).userdata =
+                        Arc::into_raw(Arc::clone(&self.control)) as *mut ffi::MMAL_PORT_USERDATA_T;
+                }
                 Some(camera_control_callback)
             };
-            let status = ffi::mmal_port_enable(self.camera.as_ref().control, cb);
+            let status = ffi::mmal_port_enable(control, cb);
             match status {
                 MMAL_STATUS_T::MMAL_SUCCESS => {
                     self.camera_port_enabled = true;
                     Ok(())
                 }
                 s => Err(
-                    MmalError::with_status("Unable to enable control port".to_owned(), s).into(),
+                    MmalError::with_status("Unable to enable control port".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
                 ),
             }
         }
@@ -311,23 +718,48 @@ impl SeriousCamera {
                     Ok(())
                 }
                 s => Err(
-                    MmalError::with_status("Unable to enable encoder port".to_owned(), s).into(),
+                    MmalError::with_status("Unable to enable encoder port".to_owned(), s)
+                        .with_stage(Stage::Encoder)
+                        .into(),
                 ),
             }
         }
     }
 
-    pub unsafe fn set_buffer_callback(&mut self, sender: SenderKind) {
-        let port = if self.use_encoder {
-            (*self.encoder.unwrap().as_ref().output.offset(0))
+    /// The part of the pipeline that still images are received from.
+    fn capture_stage(&self) -> Stage {
+        if self.use_encoder {
+            Stage::Encoder
         } else {
-            (*self.camera.as_ref().output.offset(MMAL_CAMERA_CAPTURE_PORT))
-        };
+            Stage::Camera
+        }
+    }
+
+    /// The port that still images are received from.
+    unsafe fn capture_port(&self) -> Option<*mut ffi::MMAL_PORT_T> {
+        if self.use_encoder {
+            self.encoder
+                .map(|encoder| *encoder.as_ref().output.offset(0))
+        } else {
+            Some(*self.camera.as_ref().output.offset(MMAL_CAMERA_CAPTURE_PORT))
+        }
+    }
+
+    /// Sets up the state that the capture port's callback delivers buffers with, starting with
+    /// no captures queued.
+    ///
+    /// # Safety
+    ///
+    /// The capture port must exist and have no userdata, and the still pool must have been
+    /// created. The userdata must be freed with [`drop_port_userdata`] once the port is
+    /// disabled.
+    pub(crate) unsafe fn set_buffer_callback(&mut self) {
+        let port = self.capture_port().unwrap();
 
         let userdata = Userdata {
-            pool: self.pool.unwrap(),
-            sender: sender,
-            _guard: Arc::clone(&self.mutex),
+            pool: Arc::clone(self.pool.as_ref().unwrap()),
+            captures: Mutex::new(VecDeque::new()),
+            still_port: *self.camera.as_ref().output.offset(MMAL_CAMERA_CAPTURE_PORT),
         };
 
         if !(*port).userdata.is_null() {
@@ -378,14 +810,223 @@ impl SeriousCamera {
                     "Unable to set control port parmaeter".to_owned(),
                     s,
                 )
+                .with_stage(Stage::Camera)
+                .into()),
+            }
+        }
+    }
+
+    /// Sets the exposure compensation in sixths of a stop, from -25 to 25.
+    pub fn set_exposure_compensation(&mut self, steps: i32) -> Result<(), CameraError> {
+        unsafe {
+            let status = ffi::mmal_port_parameter_set_int32(
+                self.camera.as_ref().control,
+                ffi::MMAL_PARAMETER_EXPOSURE_COMP,
+                steps,
+            );
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+                s => Err(MmalError::with_status(
+                    "Unable to set exposure compensation".to_owned(),
+                    s,
+                )
+                .with_stage(Stage::Camera)
+                .into()),
+            }
+        }
+    }
+
+    /// Returns the exposure compensation in sixths of a stop.
+    pub fn exposure_compensation(&self) -> Result<i32, CameraError> {
+        unsafe {
+            let mut steps = 0;
+            let status = ffi::mmal_port_parameter_get_int32(
+                self.camera.as_ref().control,
+                ffi::MMAL_PARAMETER_EXPOSURE_COMP,
+                &mut steps,
+            );
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(steps),
+                s => Err(MmalError::with_status(
+                    "Unable to get exposure compensation".to_owned(),
+                    s,
+                )
+                .with_stage(Stage::Camera)
                 .into()),
             }
         }
     }
 
+    /// Sets the shutter speed in microseconds. 0 lets the camera choose.
+    pub fn set_shutter_speed(&mut self, micros: u32) -> Result<(), CameraError> {
+        unsafe {
+            let status = ffi::mmal_port_parameter_set_uint32(
+                self.camera.as_ref().control,
+                ffi::MMAL_PARAMETER_SHUTTER_SPEED,
+                micros,
+            );
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+                s => Err(
+                    MmalError::with_status("Unable to set shutter speed".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
+            }
+        }
+    }
+
+    /// Returns the shutter speed in microseconds, or 0 if the camera is choosing it.
+    pub fn shutter_speed(&self) -> Result<u32, CameraError> {
+        unsafe {
+            let mut micros = 0;
+            let status = ffi::mmal_port_parameter_get_uint32(
+                self.camera.as_ref().control,
+                ffi::MMAL_PARAMETER_SHUTTER_SPEED,
+                &mut micros,
+            );
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(micros),
+                s => Err(
+                    MmalError::with_status("Unable to get shutter speed".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
+            }
+        }
+    }
+
+    /// Returns the exposure and white balance the camera most recently reported.
+    ///
+    /// The camera reports these while the control port is enabled, i.e. after
+    /// [`enable_control_port`] with `get_buffers` set to `false`. Returns `None` until the
+    /// first report arrives.
+    ///
+    /// [`enable_control_port`]: SeriousCamera::enable_control_port
+    pub fn sensor_settings(&self) -> Option<SensorSettings> {
+        *self.control.sensor.lock()
+    }
+
+    /// Returns a stream of the events the camera reports on its control port.
+    ///
+    /// Events are only reported while the control port is enabled with `get_buffers` set to
+    /// `false`, which [`SimpleCamera`] does when it is activated. Each call returns a new
+    /// stream that sees every event from then on. If the stream isn't read quickly enough,
+    /// events are dropped until there is room again.
+    ///
+    /// The stream can be read synchronously with `futures::executor::block_on_stream`.
+    pub fn events(&self) -> futures::channel::mpsc::Receiver<CameraEvent> {
+        self.control.subscribe()
+    }
+
+    /// Sets the exposure mode.
+    ///
+    /// Turning it off fixes the gains at the values the camera is currently using.
+    fn set_exposure_mode(
+        &mut self,
+        mode: ffi::MMAL_PARAM_EXPOSUREMODE_T,
+    ) -> Result<(), CameraError> {
+        unsafe {
+            let param = ffi::MMAL_PARAMETER_EXPOSUREMODE_T {
+                hdr: ffi::MMAL_PARAMETER_HEADER_T {
+                    id: ffi::MMAL_PARAMETER_EXPOSURE_MODE,
+                    size: mem::size_of::<ffi::MMAL_PARAMETER_EXPOSUREMODE_T>() as u32,
+                },
+                value: mode,
+            };
+            let status = ffi::mmal_port_parameter_set(self.camera.as_ref().control, &param.hdr);
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+                s => Err(
+                    MmalError::with_status("Unable to set exposure mode".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
+            }
+        }
+    }
+
+    /// Sets the analog and digital gains of the sensor.
+    ///
+    /// The camera only keeps these while its automatic exposure is off, e.g. after
+    /// [`SimpleCamera::lock_exposure`]. A gain of zero leaves it to the camera.
+    pub fn set_gains(&mut self, analog: f32, digital: f32) -> Result<(), CameraError> {
+        unsafe {
+            let control = self.camera.as_ref().control;
+            for &(id, gain, name) in &[
+                (ffi::MMAL_PARAMETER_ANALOG_GAIN, analog, "analog"),
+                (ffi::MMAL_PARAMETER_DIGITAL_GAIN, digital, "digital"),
+            ] {
+                let status = ffi::mmal_port_parameter_set_rational(control, id, to_rational(gain));
+                if status != MMAL_STATUS_T::MMAL_SUCCESS {
+                    return Err(MmalError::with_status(
+                        format!("Unable to set {} gain", name),
+                        status,
+                    )
+                    .with_stage(Stage::Camera)
+                    .into());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Sets the red and blue white balance gains.
+    ///
+    /// The camera only uses these while automatic white balance is off, e.g. after
+    /// [`SimpleCamera::lock_white_balance`] or when [`CameraSettings::awb_gains`] is set.
+    pub fn set_awb_gains(&mut self, red: f32, blue: f32) -> Result<(), CameraError> {
+        unsafe {
+            let param = ffi::MMAL_PARAMETER_AWB_GAINS_T {
+                hdr: ffi::MMAL_PARAMETER_HEADER_T {
+                    id: ffi::MMAL_PARAMETER_CUSTOM_AWB_GAINS,
+                    size: mem::size_of::<ffi::MMAL_PARAMETER_AWB_GAINS_T>() as u32,
+                },
+                r_gain: to_rational(red),
+                b_gain: to_rational(blue),
+            };
+            let status = ffi::mmal_port_parameter_set(self.camera.as_ref().control, &param.hdr);
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+                s => Err(
+                    MmalError::with_status("Unable to set white balance gains".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
+            }
+        }
+    }
+
+    /// Sets the white balance mode.
+    ///
+    /// Turning it off keeps the gains the camera is currently using.
+    fn set_awb_mode(&mut self, mode: ffi::MMAL_PARAM_AWBMODE_T) -> Result<(), CameraError> {
+        unsafe {
+            let param = ffi::MMAL_PARAMETER_AWBMODE_T {
+                hdr: ffi::MMAL_PARAMETER_HEADER_T {
+                    id: ffi::MMAL_PARAMETER_AWB_MODE,
+                    size: mem::size_of::<ffi::MMAL_PARAMETER_AWBMODE_T>() as u32,
+                },
+                value: mode,
+            };
+            let status = ffi::mmal_port_parameter_set(self.camera.as_ref().control, &param.hdr);
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+                s => Err(
+                    MmalError::with_status("Unable to set white balance mode".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
+            }
+        }
+    }
+
     pub fn set_camera_format(&mut self, settings: &CameraSettings) -> Result<(), CameraError> {
+        settings.check_still_resizer()?;
+
         unsafe {
             self.use_encoder = settings.use_encoder;
+            self.still_resizer_settings = settings.still_resizer.clone();
             let mut encoding = settings.encoding;
 
             let output = self.camera.as_ref().output;
@@ -399,8 +1040,8 @@ impl SeriousCamera {
             let still_port_ptr =
                 *(output.offset(MMAL_CAMERA_CAPTURE_PORT) as *mut *mut ffi::MMAL_PORT_T);
             let preview_port = *preview_port_ptr;
-            let mut video_port = *video_port_ptr;
-            let mut still_port = *still_port_ptr;
+            let video_port = *video_port_ptr;
+            let still_port = *still_port_ptr;
 
             // On firmware prior to June 2016, camera and video_splitter
             // had BGR24 and RGB24 support reversed.
@@ -420,7 +1061,20 @@ impl SeriousCamera {
             let status =
                 ffi::mmal_port_parameter_set_uint32(control, ffi::MMAL_PARAMETER_ISO, settings.iso);
             if status != MMAL_STATUS_T::MMAL_SUCCESS {
-                return Err(MmalError::with_status("Unable to set ISO".to_owned(), status).into());
+                return Err(
+                    MmalError::with_status("Unable to set ISO".to_owned(), status)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                );
+            }
+
+            // Auto is set explicitly so that gains from an earlier configuration don't stay
+            match settings.awb_gains {
+                Some(gains) => {
+                    self.set_awb_mode(ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_OFF)?;
+                    self.set_awb_gains(gains.red, gains.blue)?;
+                }
+                None => self.set_awb_mode(ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_AUTO)?,
             }
 
             let mut format = preview_port.format;
@@ -453,11 +1107,12 @@ impl SeriousCamera {
                     "Unable to set preview port format".to_owned(),
                     status,
                 )
+                .with_stage(Stage::Preview)
                 .into());
             }
 
-            if video_port.buffer_num < VIDEO_OUTPUT_BUFFERS_NUM {
-                video_port.buffer_num = VIDEO_OUTPUT_BUFFERS_NUM;
+            if (*video_port_ptr).buffer_num < VIDEO_OUTPUT_BUFFERS_NUM {
+                (*video_port_ptr).buffer_num = VIDEO_OUTPUT_BUFFERS_NUM;
             }
 
             // Set the same format on the video port (which we don't use here)
@@ -469,6 +1124,7 @@ impl SeriousCamera {
                     "Unable to set video port format".to_owned(),
                     status,
                 )
+                .with_stage(Stage::Camera)
                 .into());
             }
 
@@ -503,13 +1159,6 @@ impl SeriousCamera {
             (*es).video.frame_rate.num = 0; //STILLS_FRAME_RATE_NUM;
             (*es).video.frame_rate.den = 1; //STILLS_FRAME_RATE_DEN;
 
-            // TODO: should this be before or after the commit?
-            if still_port.buffer_size < still_port.buffer_size_min {
-                still_port.buffer_size = still_port.buffer_size_min;
-            }
-
-            still_port.buffer_num = still_port.buffer_num_recommended;
-
             let enable_zero_copy = if settings.zero_copy {
                 ffi::MMAL_TRUE
             } else {
@@ -526,6 +1175,7 @@ impl SeriousCamera {
                     format!("Unable to set zero copy to {}", settings.zero_copy),
                     status,
                 )
+                .with_stage(Stage::Camera)
                 .into());
             }
 
@@ -535,19 +1185,25 @@ impl SeriousCamera {
                     "Unable to set still port format".to_owned(),
                     status,
                 )
+                .with_stage(Stage::Camera)
                 .into());
             }
 
+            self.channel_depth = settings.pool.channel_depth;
+
             if !self.use_encoder {
-                return Ok(());
+                return set_port_buffers(still_port_ptr, &settings.pool);
             }
 
+            // The still port's buffers are allocated by the encoder
+            set_port_buffers(still_port_ptr, &PoolSettings::default())?;
+
             let encoder_in_port_ptr =
                 *(self.encoder.unwrap().as_ref().input.offset(0) as *mut *mut ffi::MMAL_PORT_T);
             let encoder_out_port_ptr =
                 *(self.encoder.unwrap().as_ref().output.offset(0) as *mut *mut ffi::MMAL_PORT_T);
             let encoder_in_port = *encoder_in_port_ptr;
-            let mut encoder_out_port = *encoder_out_port_ptr;
+            let encoder_out_port = *encoder_out_port_ptr;
 
             // We want same format on input and output
             ffi::mmal_format_copy(encoder_out_port.format, encoder_in_port.format);
@@ -555,25 +1211,18 @@ impl SeriousCamera {
             format = encoder_out_port.format;
             (*format).encoding = encoding;
 
-            encoder_out_port.buffer_size = encoder_out_port.buffer_size_recommended;
-            if encoder_out_port.buffer_size < encoder_out_port.buffer_size_min {
-                encoder_out_port.buffer_size = encoder_out_port.buffer_size_min;
-            }
-
-            encoder_out_port.buffer_num = encoder_out_port.buffer_num_recommended;
-            if encoder_out_port.buffer_num < encoder_out_port.buffer_num_min {
-                encoder_out_port.buffer_num = encoder_out_port.buffer_num_min;
-            }
-
             status = ffi::mmal_port_format_commit(encoder_out_port_ptr);
             if status != MMAL_STATUS_T::MMAL_SUCCESS {
                 return Err(MmalError::with_status(
                     "Unable to set encoder output port format".to_owned(),
                     status,
                 )
+                .with_stage(Stage::Encoder)
                 .into());
             }
 
+            set_port_buffers(encoder_out_port_ptr, &settings.pool)?;
+
             if encoding == ffi::MMAL_ENCODING_JPEG || encoding == ffi::MMAL_ENCODING_MJPEG {
                 // Set the JPEG quality level
                 status = ffi::mmal_port_parameter_set_uint32(
@@ -586,6 +1235,7 @@ impl SeriousCamera {
                         "Unable to set JPEG quality".to_owned(),
                         status,
                     )
+                    .with_stage(Stage::Encoder)
                     .into());
                 }
 
@@ -600,6 +1250,7 @@ impl SeriousCamera {
                         "Unable to set JPEG restart interval".to_owned(),
                         status,
                     )
+                    .with_stage(Stage::Encoder)
                     .into());
                 }
             }
@@ -619,8 +1270,11 @@ impl SeriousCamera {
                     self.enabled = true;
                     Ok(())
                 }
+                // Another process has the camera, so there are no resources left to enable it
+                MMAL_STATUS_T::MMAL_ENOSPC => Err(ErrorKind::CameraBusy.into()),
                 s => Err(
                     MmalError::with_status("Unable to enable camera component".to_owned(), s)
+                        .with_stage(Stage::Camera)
                         .into(),
                 ),
             }
@@ -644,6 +1298,7 @@ impl SeriousCamera {
                             "Unable to enable encoder component".to_owned(),
                             s,
                         )
+                        .with_stage(Stage::Encoder)
                         .into()),
                     }
                 }
@@ -651,6 +1306,7 @@ impl SeriousCamera {
                     "Unable to enable encoder control port".to_owned(),
                     s,
                 )
+                .with_stage(Stage::Encoder)
                 .into()),
             }
         }
@@ -665,40 +1321,27 @@ impl SeriousCamera {
                     // self.enabled = true;
                     Ok(())
                 }
-                s => Err(MmalError::with_status("Unable to enable preview".to_owned(), s).into()),
+                s => Err(
+                    MmalError::with_status("Unable to enable preview".to_owned(), s)
+                        .with_stage(Stage::Preview)
+                        .into(),
+                ),
             }
         }
     }
 
     pub fn create_pool(&mut self) -> Result<(), CameraError> {
         unsafe {
-            let port_ptr = if self.use_encoder {
-                let output = self.encoder.unwrap().as_ref().output;
-                *(output.offset(0) as *mut *mut ffi::MMAL_PORT_T)
+            let (component, port_ptr) = if self.use_encoder {
+                let encoder = self.encoder.unwrap();
+                (encoder, *encoder.as_ref().output.offset(0))
             } else {
                 let output = self.camera.as_ref().output;
-                *(output.offset(MMAL_CAMERA_CAPTURE_PORT) as *mut *mut ffi::MMAL_PORT_T)
+                (self.camera, *output.offset(MMAL_CAMERA_CAPTURE_PORT))
             };
 
-            let pool = ffi::mmal_port_pool_create(
-                port_ptr,
-                (*port_ptr).buffer_num,
-                (*port_ptr).buffer_size,
-            );
-
-            if pool.is_null() {
-                Err(MmalError::with_status(
-                    format!(
-                        "Failed to create buffer header pool for camera port {}",
-                        CStr::from_ptr((*port_ptr).name).to_string_lossy()
-                    ),
-                    MMAL_STATUS_T::MMAL_STATUS_MAX, // there is no status here unusually
-                )
-                .into())
-            } else {
-                self.pool = Some(NonNull::new(pool).unwrap());
-                Ok(())
-            }
+            self.pool = Some(Arc::new(StillPool::new(component, port_ptr)?));
+            Ok(())
         }
     }
 
@@ -723,6 +1366,7 @@ impl SeriousCamera {
                     "Unable to create null sink for preview".to_owned(),
                     s,
                 )
+                .with_stage(Stage::Preview)
                 .into()),
             }
         }
@@ -754,7 +1398,9 @@ impl SeriousCamera {
                     Ok(())
                 }
                 s => Err(
-                    MmalError::with_status("Unable to connect preview ports".to_owned(), s).into(),
+                    MmalError::with_status("Unable to connect preview ports".to_owned(), s)
+                        .with_stage(Stage::Connection)
+                        .into(),
                 ),
             }
         }
@@ -764,7 +1410,8 @@ impl SeriousCamera {
         &mut self,
         buffer_port_ptr: *mut ffi::MMAL_PORT_T,
     ) -> Result<(), CameraError> {
-        let num = ffi::mmal_queue_length(self.pool.unwrap().as_ref().queue as *mut _);
+        let queue = self.pool.as_ref().unwrap().queue();
+        let num = ffi::mmal_queue_length(queue);
         debug!("got length {}", num);
 
         debug!(
@@ -774,15 +1421,12 @@ impl SeriousCamera {
         );
 
         for i in 0..num {
-            let buffer = ffi::mmal_queue_get(self.pool.unwrap().as_ref().queue);
+            let buffer = ffi::mmal_queue_get(queue);
             debug!("got buffer {}", i);
 
             if buffer.is_null() {
-                return Err(MmalError::with_status(
-                    format!("Unable to get a required buffer {} from pool queue", i),
-                    MMAL_STATUS_T::MMAL_STATUS_MAX,
-                )
-                .into());
+                debug!("Unable to get a required buffer {} from pool queue", i);
+                return Err(ErrorKind::PoolExhausted(Stage::Pool).into());
             } else {
                 let status = ffi::mmal_port_send_buffer(buffer_port_ptr, buffer);
                 if status != MMAL_STATUS_T::MMAL_SUCCESS {
@@ -790,6 +1434,7 @@ impl SeriousCamera {
                         format!("Unable to send a buffer to camera output port ({})", i),
                         status,
                     )
+                    .with_stage(Stage::Camera)
                     .into());
                 }
             }
@@ -798,261 +1443,1010 @@ impl SeriousCamera {
         Ok(())
     }
 
-    fn do_take(
-        &mut self,
-        buffer_port_ptr: &mut *mut ffi::MMAL_PORT_T,
-        is_async: bool,
-    ) -> Result<ReceiverKind, CameraError> {
-        unsafe {
-            let mut status = ffi::mmal_port_parameter_set_uint32(
-                self.camera.as_ref().control,
-                ffi::MMAL_PARAMETER_SHUTTER_SPEED as u32,
-                0, // 0 = auto
-            );
-
-            if status != ffi::MMAL_STATUS_T::MMAL_SUCCESS {
-                return Err(MmalError::with_status(
-                    "Unable to set shutter speed".to_owned(),
-                    status,
-                )
-                .into());
-            }
-
-            if self.use_encoder {
-                if !self.encoder_output_port_enabled {
-                    self.enable_encoder_port().unwrap();
-                }
-            } else {
-                if !self.still_port_enabled {
-                    self.enable_still_port().unwrap();
-                }
+    /// Enables the capture port and hands it the pool's buffers, ready for captures to be
+    /// queued.
+    unsafe fn start_captures(&mut self, port: *mut ffi::MMAL_PORT_T) -> Result<(), CameraError> {
+        if self.use_encoder {
+            if !self.encoder_output_port_enabled {
+                self.enable_encoder_port()?;
             }
+        } else if !self.still_port_enabled {
+            self.enable_still_port().map_err(|s| {
+                MmalError::with_status("Unable to enable still port".to_owned(), s)
+                    .with_stage(Stage::Camera)
+            })?;
+        }
 
-            let output = self.camera.as_ref().output;
+        self.set_buffer_callback();
 
-            let still_port_ptr =
-                *(output.offset(MMAL_CAMERA_CAPTURE_PORT) as *mut *mut ffi::MMAL_PORT_T);
+        // Send all the buffers to the capture port
+        if let Err(err) = self.send_buffers(port) {
+            if let Err(_err) = self.stop_captures() {
+                debug!("Unable to stop captures: {}", _err);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
 
-            if self.use_encoder {
-                let encoder_out_port_ptr =
-                    *(self.encoder.unwrap().as_ref().output as *mut *mut ffi::MMAL_PORT_T);
-                *buffer_port_ptr = encoder_out_port_ptr;
-            } else {
-                *buffer_port_ptr = still_port_ptr;
+    /// Queues a capture, returning its receiver and the id to cancel it with.
+    fn queue_capture(&mut self, is_async: bool) -> Result<(ReceiverKind, u64), CameraError> {
+        unsafe {
+            if !self.enabled || (self.use_encoder && !self.encoder_enabled) {
+                return Err(ErrorKind::InvalidState(self.capture_stage()).into());
+            }
+            let buffers = match &self.pool {
+                Some(pool) => pool.pool.as_ref().headers_num as usize,
+                None => return Err(ErrorKind::InvalidState(Stage::Pool).into()),
+            };
+            let depth = self.channel_depth.unwrap_or(buffers);
+            if self.in_flight.load(Ordering::SeqCst) >= self.capture_queue_depth {
+                return Err(ErrorKind::QueueFull(self.capture_stage()).into());
             }
 
-            // Send all the buffers to the camera output port
-            self.send_buffers(*buffer_port_ptr)?;
+            let port = self.capture_port().unwrap();
+            if (*port).userdata.is_null() {
+                self.start_captures(port)?;
+            }
 
+            // By default there is room for every buffer, and the end of the image, so that the
+            // callback never finds the channel full
             let (sender, receiver) = if is_async {
-                let (sender, receiver) = futures::channel::mpsc::channel(0);
+                let (sender, receiver) = futures::channel::mpsc::channel(depth);
                 (
                     SenderKind::AsyncSender(sender),
                     ReceiverKind::AsyncReceiver(receiver),
                 )
             } else {
-                let (sender, receiver) = mpsc::sync_channel(0);
+                let (sender, receiver) = mpsc::sync_channel(depth + 1);
                 (
                     SenderKind::SyncSender(sender),
                     ReceiverKind::SyncReceiver(receiver),
                 )
             };
 
-            self.set_buffer_callback(sender);
+            let userdata = &*((*port).userdata as *const Userdata);
+            let id = self.next_capture_id;
+            self.next_capture_id += 1;
+            let capture = QueuedCapture {
+                sender: Some(sender),
+                ticket: CaptureTicket::new(&self.in_flight),
+                id,
+            };
+            let first = {
+                let mut captures = userdata.captures.lock();
+                captures.push_back(capture);
+                captures.len() == 1
+            };
 
-            status = ffi::mmal_port_parameter_set_boolean(
-                still_port_ptr,
-                ffi::MMAL_PARAMETER_CAPTURE as u32,
-                1,
+            // Otherwise the capture is armed once the ones before it have finished
+            if first {
+                if let Err(err) = arm_capture(userdata.still_port) {
+                    userdata.captures.lock().pop_back();
+                    return Err(err);
+                }
+                debug!("Started capture");
+            } else {
+                debug!("Queued capture");
+            }
+
+            Ok((receiver, id))
+        }
+    }
+
+    /// Queues a capture of a still image, returning a receiver for the buffers of the image.
+    ///
+    /// Images are captured one after another in the order they were queued, so a capture can
+    /// be queued while an earlier image is still being received or its buffers are still held.
+    /// At most [`capture_queue_depth`] captures can be in progress at once. After that this
+    /// fails with [`ErrorKind::QueueFull`] until earlier images have been received and their
+    /// buffers have been dropped.
+    ///
+    /// Dropping the receiver cancels the capture. The capture is disarmed as soon as the camera
+    /// next delivers a buffer, and the rest of the image is handed straight back. If a buffer
+    /// can't be delivered because the receiver has fallen too far behind, the capture fails in
+    /// the same way and the receiver is disconnected before it gets a complete buffer.
+    ///
+    /// [`capture_queue_depth`]: SeriousCamera::capture_queue_depth
+    pub fn take(&mut self) -> Result<mpsc::Receiver<Option<BufferGuard>>, CameraError> {
+        match self.queue_capture(false)?.0 {
+            ReceiverKind::SyncReceiver(receiver) => Ok(receiver),
+            ReceiverKind::AsyncReceiver(_) => unreachable!(),
+        }
+    }
+
+    /// Queues a capture of a still image, returning a stream of the buffers of the image.
+    ///
+    /// See [`take`](SeriousCamera::take).
+    pub fn take_async(
+        &mut self,
+    ) -> Result<futures::channel::mpsc::Receiver<BufferGuard>, CameraError> {
+        match self.queue_capture(true)?.0 {
+            ReceiverKind::AsyncReceiver(receiver) => Ok(receiver),
+            ReceiverKind::SyncReceiver(_) => unreachable!(),
+        }
+    }
+
+    /// Sets how many still captures can be in progress at once. A capture is in progress from
+    /// when it is queued until the image has been captured and all of its buffers have been
+    /// dropped.
+    ///
+    /// The depth is at least 1. Defaults to 2, so that the next image can be captured while the
+    /// last one is being processed.
+    pub fn set_capture_queue_depth(&mut self, depth: usize) {
+        self.capture_queue_depth = depth.max(1);
+    }
+
+    pub fn capture_queue_depth(&self) -> usize {
+        self.capture_queue_depth
+    }
+
+    /// Cancels every capture queued with [`take`] or [`take_async`] that hasn't completed.
+    ///
+    /// The capture is disarmed and the buffers the camera is holding are returned to the pool.
+    /// The receivers for the cancelled captures are disconnected. Does nothing if no capture is
+    /// waiting for the camera.
+    ///
+    /// [`take`]: SeriousCamera::take
+    /// [`take_async`]: SeriousCamera::take_async
+    pub fn cancel_take(&mut self) -> Result<(), CameraError> {
+        unsafe {
+            let waiting = match self.capture_port() {
+                Some(port) if !(*port).userdata.is_null() => {
+                    let userdata = &*((*port).userdata as *const Userdata);
+                    let waiting = !userdata.captures.lock().is_empty();
+                    waiting
+                }
+                _ => false,
+            };
+
+            if waiting {
+                self.stop_captures()
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Cancels the capture queued as `id` if it hasn't completed, leaving the others queued.
+    ///
+    /// A capture that is still waiting for its turn is just removed. The capture at the front
+    /// may have buffers of its image in the port, so the port is restarted to flush them before
+    /// the next capture is armed.
+    fn cancel_capture(&mut self, id: u64) -> Result<(), CameraError> {
+        unsafe {
+            let port = match self.capture_port() {
+                Some(port) if !(*port).userdata.is_null() => port,
+                _ => return Ok(()),
+            };
+
+            let rest = {
+                let userdata = &*((*port).userdata as *const Userdata);
+                let mut captures = userdata.captures.lock();
+                match captures.iter().position(|capture| capture.id == id) {
+                    Some(0) => {
+                        captures.pop_front();
+                        mem::replace(&mut *captures, VecDeque::new())
+                    }
+                    Some(i) => {
+                        captures.remove(i);
+                        return Ok(());
+                    }
+                    // Already finished
+                    None => return Ok(()),
+                }
+            };
+            debug!(
+                "Cancelling the current capture, {} still queued",
+                rest.len()
             );
 
-            // if self.use_encoder {
-            //     status = ffi::mmal_port_parameter_set_boolean(buffer_port_ptr, ffi::MMAL_PARAMETER_EXIF_DISABLE, 1);
-            // }
+            let stopped = self.stop_captures();
+            if !rest.is_empty() {
+                // The ones left behind haven't received anything, so they carry on as they were
+                self.start_captures(port)?;
+                let userdata = &*((*port).userdata as *const Userdata);
+                *userdata.captures.lock() = rest;
+                arm_next_capture(userdata, true);
+            }
+            stopped
+        }
+    }
 
-            match status {
-                MMAL_STATUS_T::MMAL_SUCCESS => {
-                    debug!("Started capture");
+    /// Disarms the capture, disables the capture port and drops every queued capture.
+    unsafe fn stop_captures(&mut self) -> Result<(), CameraError> {
+        let port = match self.capture_port() {
+            Some(port) if !(*port).userdata.is_null() => port,
+            _ => return Ok(()),
+        };
+        debug!("stopping captures");
+
+        // Nothing can be armed again once the queue is empty
+        let userdata = &*((*port).userdata as *const Userdata);
+        userdata.captures.lock().clear();
+
+        let disarmed = disarm_capture(userdata.still_port);
+
+        // Waits for any callback in progress and flushes the port's buffers back to the pool.
+        // The port is enabled again by the next capture.
+        ffi::mmal_port_disable(port);
+        if self.use_encoder {
+            self.encoder_output_port_enabled = false;
+        } else {
+            self.still_port_enabled = false;
+        }
+
+        drop_port_userdata(port);
+
+        disarmed
+    }
+
+    /// Sets the format of the video port.
+    ///
+    /// The camera component is briefly disabled if it is already enabled because the format
+    /// can't be changed while it is running.
+    fn set_video_format(
+        &mut self,
+        encoding: u32,
+        width: u32,
+        height: u32,
+        frame_rate: u32,
+    ) -> Result<(), CameraError> {
+        unsafe {
+            let video_port_ptr = *self.camera.as_ref().output.offset(MMAL_CAMERA_VIDEO_PORT);
 
-                    Ok(receiver)
+            let format = (*video_port_ptr).format;
+            (*format).encoding = fixed_rgb_order(video_port_ptr, encoding);
+            (*format).encoding_variant = 0;
+
+            let es = (*format).es;
+            (*es).video.width = ffi::vcos_align_up(width, 32);
+            (*es).video.height = ffi::vcos_align_up(height, 16);
+            (*es).video.crop.x = 0;
+            (*es).video.crop.y = 0;
+            (*es).video.crop.width = width as i32;
+            (*es).video.crop.height = height as i32;
+            (*es).video.frame_rate.num = frame_rate as i32;
+            (*es).video.frame_rate.den = 1;
+
+            if self.enabled {
+                ffi::mmal_component_disable(self.camera.as_ptr());
+            }
+
+            let status = ffi::mmal_port_format_commit(video_port_ptr);
+
+            if self.enabled {
+                let status = ffi::mmal_component_enable(self.camera.as_ptr());
+                if status != MMAL_STATUS_T::MMAL_SUCCESS {
+                    self.enabled = false;
+                    return Err(MmalError::with_status(
+                        "Unable to re-enable camera component".to_owned(),
+                        status,
+                    )
+                    .with_stage(Stage::Camera)
+                    .into());
                 }
-                s => Err(MmalError::with_status(
-                    "Unable to set camera capture boolean".to_owned(),
-                    s,
-                )
-                .into()),
+            }
+
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+                s => Err(
+                    MmalError::with_status("Unable to set video port format".to_owned(), s)
+                        .with_stage(Stage::Camera)
+                        .into(),
+                ),
             }
         }
     }
 
-    pub fn take(&mut self) -> Result<mpsc::Receiver<Option<BufferGuard>>, CameraError> {
+    /// Starts streaming raw frames from the video port.
+    ///
+    /// The camera must be set up and enabled first, as it would be for [`take`].
+    ///
+    /// [`take`]: SeriousCamera::take
+    pub fn stream(&mut self, settings: &StreamSettings) -> Result<FrameStream<'_>, CameraError> {
+        self.set_video_format(
+            settings.format.encoding(),
+            settings.width,
+            settings.height,
+            settings.frame_rate,
+        )?;
+
         unsafe {
-            self.mutex.raw().lock();
+            let video_port_ptr = *self.camera.as_ref().output.offset(MMAL_CAMERA_VIDEO_PORT);
+            let mut pipeline = Pipeline::default();
+            let sink = PortSink::new(video_port_ptr, settings.buffers, Stage::Camera)?;
+            pipeline.start_capture(video_port_ptr)?;
+
+            Ok(FrameStream::new(
+                sink,
+                Arc::new(pipeline),
+                settings.format,
+                settings.width,
+                settings.height,
+            ))
         }
+    }
 
-        let mut buffer_port_ptr = ptr::null_mut();
-        let mutex = Arc::clone(&self.mutex);
+    /// Splits the video port into up to four outputs using the video splitter.
+    ///
+    /// Every output receives the same frames at the size and rate in `settings`. Each is either
+    /// encoded or delivered as raw frames, and the streams are returned in the same order as
+    /// `outputs`. Capturing continues until all of the streams have been dropped.
+    ///
+    /// The camera must be set up and enabled first, as it would be for [`take`].
+    ///
+    /// [`take`]: SeriousCamera::take
+    pub fn split(
+        &mut self,
+        settings: &VideoSettings,
+        outputs: &[SplitterOutput],
+    ) -> Result<Vec<SplitterStream<'_>>, CameraError> {
+        if outputs.is_empty() || outputs.len() > MAX_SPLITTER_OUTPUTS {
+            return Err(ErrorKind::InvalidArgument(
+                Stage::Splitter,
+                format!(
+                    "The splitter needs between 1 and {} outputs, got {}",
+                    MAX_SPLITTER_OUTPUTS,
+                    outputs.len()
+                ),
+            )
+            .into());
+        }
 
-        defer_on_unwind! {{
-            unsafe { mutex.force_unlock() };
-        }}
+        self.set_video_format(
+            ffi::MMAL_ENCODING_OPAQUE,
+            settings.width,
+            settings.height,
+            settings.frame_rate,
+        )?;
 
-        self.do_take(&mut buffer_port_ptr, false)
-            .map_err(|e| {
-                unsafe {
-                    if !buffer_port_ptr.is_null() && !(*buffer_port_ptr).userdata.is_null() {
-                        drop_port_userdata(buffer_port_ptr);
+        unsafe {
+            let video_port_ptr = *self.camera.as_ref().output.offset(MMAL_CAMERA_VIDEO_PORT);
+            let mut pipeline = Pipeline::default();
+
+            let splitter =
+                Component::new(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_SPLITTER, Stage::Splitter)?;
+            let splitter_input = splitter.input(0);
+            if splitter.output_num() < outputs.len() {
+                return Err(ErrorKind::InvalidArgument(
+                    Stage::Splitter,
+                    format!("The splitter only has {} outputs", splitter.output_num()),
+                )
+                .into());
+            }
+            let splitter_outputs: Vec<_> = (0..outputs.len())
+                .map(|i| splitter.output(i as isize))
+                .collect();
+            pipeline.add_component(splitter);
+            pipeline.add_connection(Connection::new(video_port_ptr, splitter_input)?);
+
+            // The port each stream reads from
+            let mut ports = Vec::with_capacity(outputs.len());
+            for (output, &port) in outputs.iter().zip(splitter_outputs.iter()) {
+                ffi::mmal_format_copy((*port).format, (*splitter_input).format);
+                match output {
+                    SplitterOutput::Encoder(_) => {}
+                    SplitterOutput::Raw { format, .. } => {
+                        (*(*port).format).encoding = fixed_rgb_order(port, format.encoding());
+                    }
+                    // The resizers don't accept opaque frames
+                    SplitterOutput::Resizer(_) => {
+                        (*(*port).format).encoding = ffi::MMAL_ENCODING_I420;
                     }
-                    self.mutex.force_unlock();
                 }
-                e
-            })
-            .map(|receiver| match receiver {
-                ReceiverKind::SyncReceiver(receiver) => receiver,
-                ReceiverKind::AsyncReceiver(_) => unreachable!(),
-            })
+
+                let status = ffi::mmal_port_format_commit(port);
+                if status != MMAL_STATUS_T::MMAL_SUCCESS {
+                    return Err(MmalError::with_status(
+                        "Unable to set splitter output format".to_owned(),
+                        status,
+                    )
+                    .with_stage(Stage::Splitter)
+                    .into());
+                }
+
+                match output {
+                    SplitterOutput::Encoder(encoder_settings) => {
+                        let encoder = create_video_encoder(port, encoder_settings, &mut pipeline)?;
+                        ports.push((encoder, encoder_settings.buffers, Stage::Encoder));
+                    }
+                    SplitterOutput::Raw { buffers, .. } => {
+                        ports.push((port, *buffers, Stage::Splitter))
+                    }
+                    SplitterOutput::Resizer(resizer_settings) => {
+                        let resizer = create_resizer(port, resizer_settings, &mut pipeline)?;
+                        ports.push((resizer, resizer_settings.buffers, Stage::Resizer));
+                    }
+                }
+            }
+
+            pipeline.enable()?;
+
+            // Declared after the pipeline so that the sinks are dropped first on error
+            let mut sinks = Vec::with_capacity(outputs.len());
+            for &(port, buffers, stage) in ports.iter() {
+                sinks.push(PortSink::new(port, buffers, stage)?);
+            }
+
+            pipeline.start_capture(video_port_ptr)?;
+            let pipeline = Arc::new(pipeline);
+
+            Ok(outputs
+                .iter()
+                .zip(sinks)
+                .map(|(output, sink)| match output {
+                    SplitterOutput::Encoder(_) => SplitterStream::Video(VideoStream::new(
+                        sink,
+                        pipeline.clone(),
+                        settings.width,
+                        settings.height,
+                    )),
+                    SplitterOutput::Raw { format, .. } => SplitterStream::Frames(FrameStream::new(
+                        sink,
+                        pipeline.clone(),
+                        *format,
+                        settings.width,
+                        settings.height,
+                    )),
+                    SplitterOutput::Resizer(resizer_settings) => {
+                        SplitterStream::Frames(FrameStream::new(
+                            sink,
+                            pipeline.clone(),
+                            resizer_settings.format,
+                            resizer_settings.width,
+                            resizer_settings.height,
+                        ))
+                    }
+                })
+                .collect())
+        }
     }
 
-    pub fn take_async(
+    /// Streams frames from the video port after scaling them in hardware.
+    ///
+    /// The camera produces frames at the size and rate in `settings`, which the resizer scales
+    /// to its own size and format. This leaves the still port free, so a full resolution still
+    /// can be taken while the scaled frames are used as a preview. Use [`split`] with a
+    /// [`SplitterOutput::Resizer`] to also record the unscaled frames.
+    ///
+    /// The scaled frames are separate captures from any still taken at the same time. To scale
+    /// the still images themselves, set [`CameraSettings::still_resizer`].
+    ///
+    /// The camera must be set up and enabled first, as it would be for [`take`].
+    ///
+    /// [`split`]: SeriousCamera::split
+    /// [`take`]: SeriousCamera::take
+    pub fn resize(
         &mut self,
-    ) -> Result<futures::channel::mpsc::Receiver<BufferGuard>, CameraError> {
+        settings: &VideoSettings,
+        resizer: &ResizerSettings,
+    ) -> Result<FrameStream<'_>, CameraError> {
+        // The resizers don't accept opaque frames
+        self.set_video_format(
+            ffi::MMAL_ENCODING_I420,
+            settings.width,
+            settings.height,
+            settings.frame_rate,
+        )?;
+
         unsafe {
-            self.mutex.raw().lock();
+            let video_port_ptr = *self.camera.as_ref().output.offset(MMAL_CAMERA_VIDEO_PORT);
+            let mut pipeline = Pipeline::default();
+            let output = create_resizer(video_port_ptr, resizer, &mut pipeline)?;
+            pipeline.enable()?;
+
+            let sink = PortSink::new(output, resizer.buffers, Stage::Resizer)?;
+            pipeline.start_capture(video_port_ptr)?;
+
+            Ok(FrameStream::new(
+                sink,
+                Arc::new(pipeline),
+                resizer.format,
+                resizer.width,
+                resizer.height,
+            ))
         }
+    }
+}
 
-        let mut buffer_port_ptr = ptr::null_mut();
-        let mutex = Arc::clone(&self.mutex);
+/// Checks a buffer of a still image, returning whether it is the last one.
+fn check_buffer(
+    buf: &BufferGuard,
+    stage: Stage,
+    jpeg: &mut Option<JpegMarkers>,
+) -> Result<bool, CameraError> {
+    if buf.is_failed() {
+        return Err(ErrorKind::CaptureFailed(stage).into());
+    }
+
+    if let Some(jpeg) = jpeg {
+        jpeg.push(buf.get_bytes());
+        if buf.is_complete() && !jpeg.is_valid() {
+            debug!("The image is not a complete JPEG");
+            return Err(ErrorKind::CaptureFailed(stage).into());
+        }
+    }
 
-        defer_on_unwind! {{
-            unsafe { mutex.force_unlock() };
-        }}
+    Ok(buf.is_complete())
+}
 
-        self.do_take(&mut buffer_port_ptr, true)
-            .map_err(|e| {
-                unsafe {
-                    if buffer_port_ptr != ptr::null_mut()
-                        && (*buffer_port_ptr).userdata != ptr::null_mut()
-                    {
-                        drop_port_userdata(buffer_port_ptr);
-                    }
-                    self.mutex.force_unlock();
-                }
-                e
-            })
-            .map(|receiver| match receiver {
-                ReceiverKind::AsyncReceiver(receiver) => receiver,
-                ReceiverKind::SyncReceiver(_) => unreachable!(),
-            })
+/// Drops the receiver for a capture and then cancels it if it is still in progress.
+fn cancel_capture<R>((receiver, id, camera): (R, u64, &mut SeriousCamera)) {
+    drop(receiver);
+    if let Err(_err) = camera.cancel_capture(id) {
+        debug!("Unable to cancel capture: {}", _err);
     }
 }
 
+/// Returns a future that completes after `duration` without relying on any particular runtime.
+///
+/// The thread behind it stops early once the future is dropped.
+fn delay(duration: Duration) -> futures::channel::oneshot::Receiver<()> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    let deadline = Instant::now() + duration;
+    thread::spawn(move || {
+        while !sender.is_canceled() {
+            let now = Instant::now();
+            if now >= deadline {
+                let _ = sender.send(());
+                return;
+            }
+            thread::sleep((deadline - now).min(DELAY_POLL_INTERVAL));
+        }
+    });
+    receiver
+}
+
+/// Returns the encoding to ask for so that RGB24 and BGR24 come out as requested.
+///
+/// # Safety
+///
+/// `port` must be a valid camera or video splitter port.
+unsafe fn fixed_rgb_order(port: *mut ffi::MMAL_PORT_T, encoding: u32) -> u32 {
+    // On firmware prior to June 2016, camera and video_splitter
+    // had BGR24 and RGB24 support reversed.
+    let rgb = encoding == ffi::MMAL_ENCODING_RGB24 || encoding == ffi::MMAL_ENCODING_BGR24;
+    if !rgb || ffi::mmal_util_rgb_order_fixed(port) == 1 {
+        encoding
+    } else if encoding == ffi::MMAL_ENCODING_RGB24 {
+        ffi::MMAL_ENCODING_BGR24
+    } else {
+        ffi::MMAL_ENCODING_RGB24
+    }
+}
+
+/// Creates a video encoder fed by `source` and adds it to the pipeline.
+///
+/// Returns the encoder's output port, which has been configured but not enabled.
+///
+/// # Safety
+///
+/// `source` must be a valid output port whose format has been committed.
+unsafe fn create_video_encoder(
+    source: *mut ffi::MMAL_PORT_T,
+    settings: &EncoderSettings,
+    pipeline: &mut Pipeline,
+) -> Result<*mut ffi::MMAL_PORT_T, CameraError> {
+    let encoder = Component::new(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_ENCODER, Stage::Encoder)?;
+    let input = encoder.input(0);
+    let output = encoder.output(0);
+    pipeline.add_component(encoder);
+    pipeline.add_connection(Connection::new(source, input)?);
+
+    ffi::mmal_format_copy((*output).format, (*input).format);
+    let format = (*output).format;
+    (*format).encoding = settings.encoding;
+    (*format).bitrate = settings.bitrate;
+    // Variable frame rate
+    (*(*format).es).video.frame_rate.num = 0;
+    (*(*format).es).video.frame_rate.den = 1;
+
+    let status = ffi::mmal_port_format_commit(output);
+    if status != MMAL_STATUS_T::MMAL_SUCCESS {
+        return Err(MmalError::with_status(
+            "Unable to set encoder output format".to_owned(),
+            status,
+        )
+        .with_stage(Stage::Encoder)
+        .into());
+    }
+
+    if let Some(intra_period) = settings.intra_period {
+        let status = ffi::mmal_port_parameter_set_uint32(
+            output,
+            ffi::MMAL_PARAMETER_INTRAPERIOD,
+            intra_period,
+        );
+        if status != MMAL_STATUS_T::MMAL_SUCCESS {
+            return Err(MmalError::with_status(
+                "Unable to set encoder intra period".to_owned(),
+                status,
+            )
+            .with_stage(Stage::Encoder)
+            .into());
+        }
+    }
+
+    let status = ffi::mmal_port_parameter_set_boolean(
+        output,
+        ffi::MMAL_PARAMETER_VIDEO_ENCODE_INLINE_HEADER,
+        settings.inline_headers as i32,
+    );
+    if status != MMAL_STATUS_T::MMAL_SUCCESS {
+        return Err(MmalError::with_status(
+            "Unable to set encoder inline headers".to_owned(),
+            status,
+        )
+        .with_stage(Stage::Encoder)
+        .into());
+    }
+
+    let status = ffi::mmal_port_parameter_set_boolean(
+        output,
+        ffi::MMAL_PARAMETER_VIDEO_ENCODE_INLINE_VECTORS,
+        settings.inline_motion_vectors as i32,
+    );
+    if status != MMAL_STATUS_T::MMAL_SUCCESS {
+        return Err(MmalError::with_status(
+            "Unable to set encoder inline motion vectors".to_owned(),
+            status,
+        )
+        .with_stage(Stage::Encoder)
+        .into());
+    }
+
+    // Lets the encoder use the input buffers as reference frames
+    let status =
+        ffi::mmal_port_parameter_set_boolean(input, ffi::MMAL_PARAMETER_VIDEO_IMMUTABLE_INPUT, 1);
+    if status != MMAL_STATUS_T::MMAL_SUCCESS {
+        return Err(MmalError::with_status(
+            "Unable to set encoder immutable input".to_owned(),
+            status,
+        )
+        .with_stage(Stage::Encoder)
+        .into());
+    }
+
+    Ok(output)
+}
+
+/// Creates a resizer fed by `source` and adds it to the pipeline.
+///
+/// Returns the resizer's output port, which has been configured but not enabled.
+///
+/// # Safety
+///
+/// `source` must be a valid output port whose format has been committed.
+unsafe fn create_resizer(
+    source: *mut ffi::MMAL_PORT_T,
+    settings: &ResizerSettings,
+    pipeline: &mut Pipeline,
+) -> Result<*mut ffi::MMAL_PORT_T, CameraError> {
+    let resizer = Component::new(settings.kind.component_name(), Stage::Resizer)?;
+    let input = resizer.input(0);
+    let output = resizer.output(0);
+    pipeline.add_component(resizer);
+    pipeline.add_connection(Connection::new(source, input)?);
+
+    ffi::mmal_format_copy((*output).format, (*input).format);
+    let format = (*output).format;
+    (*format).encoding = settings.format.encoding();
+    (*format).encoding_variant = 0;
+
+    let es = (*format).es;
+    (*es).video.width = ffi::vcos_align_up(settings.width, 32);
+    (*es).video.height = ffi::vcos_align_up(settings.height, 16);
+    (*es).video.crop.x = 0;
+    (*es).video.crop.y = 0;
+    (*es).video.crop.width = settings.width as i32;
+    (*es).video.crop.height = settings.height as i32;
+
+    let status = ffi::mmal_port_format_commit(output);
+    if status != MMAL_STATUS_T::MMAL_SUCCESS {
+        return Err(MmalError::with_status(
+            "Unable to set resizer output format".to_owned(),
+            status,
+        )
+        .with_stage(Stage::Resizer)
+        .into());
+    }
+
+    Ok(output)
+}
+
 unsafe extern "C" fn camera_buffer_callback(
     port: *mut ffi::MMAL_PORT_T,
     buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
 ) {
-    let bytes_to_write = (*buffer).length;
-    let pdata_ptr: *mut Userdata = (*port).userdata as *mut Userdata;
-    let mut complete = false;
+    // Unwinding into C is undefined behaviour
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handle_camera_buffer(port, buffer)));
+    if result.is_err() {
+        debug!("Panicked while handling a camera buffer");
+        // Fail the capture rather than risk handing its receiver a broken image
+        let pdata_ptr = (*port).userdata as *const Userdata;
+        if !pdata_ptr.is_null() {
+            if let Some(capture) = (*pdata_ptr).captures.lock().front_mut() {
+                capture.sender = None;
+            }
+        }
+    }
+}
 
-    debug!("I'm called from C. buffer length: {}", bytes_to_write);
+unsafe fn handle_camera_buffer(
+    port: *mut ffi::MMAL_PORT_T,
+    buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
+) {
+    let bytes_to_write = (*buffer).length;
+    let flags = (*buffer).flags;
+    let pdata_ptr = (*port).userdata as *const Userdata;
 
-    if !pdata_ptr.is_null() {
-        let userdata: &mut Userdata = &mut *pdata_ptr;
+    debug!(
+        "I'm called from C. buffer length: {}, flags: 0x{:x}",
+        bytes_to_write, flags
+    );
 
-        // Check end of frame or error
-        if ((*buffer).flags
-            & (ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END
-                | ffi::MMAL_BUFFER_HEADER_FLAG_TRANSMISSION_FAILED))
-            > 0
-        {
-            complete = true;
+    if pdata_ptr.is_null() {
+        debug!("Received a camera still buffer callback with no state");
+        ffi::mmal_buffer_header_release(buffer);
+        return;
+    }
+    let userdata = &*pdata_ptr;
+
+    let mut captures = userdata.captures.lock();
+    let capture = match captures.front_mut() {
+        Some(capture) => capture,
+        None => {
+            // The captures have been cancelled and the port is being flushed
+            debug!("Received a camera still buffer with no capture waiting for it");
+            ffi::mmal_buffer_header_release(buffer);
+            return;
         }
+    };
 
-        if bytes_to_write > 0 {
-            ffi::mmal_buffer_header_mem_lock(buffer);
+    let (complete, failed) = still_buffer_state(flags);
 
-            match &mut userdata.sender {
-                SenderKind::AsyncSender(sender) => {
-                    sender
-                        .try_send(BufferGuard::new(port, buffer, userdata.pool, complete))
-                        .unwrap();
-                }
-                SenderKind::SyncSender(sender) => {
-                    sender
-                        .send(Some(BufferGuard::new(
-                            port,
-                            buffer,
-                            userdata.pool,
-                            complete,
-                        )))
-                        .unwrap();
+    ffi::mmal_buffer_header_mem_lock(buffer);
+    let pool = Arc::clone(&userdata.pool);
+    let ticket = Arc::clone(&capture.ticket);
+    let guard = BufferGuard::new(port, buffer, pool, ticket, complete, failed);
+
+    // An empty buffer only needs delivering if it ends the image. Otherwise it goes straight
+    // back.
+    let delivery = if bytes_to_write > 0 || complete {
+        capture.deliver(guard)
+    } else {
+        Delivery::Discarded(guard)
+    };
+
+    // The capture has finished, and a failed capture stays at the front until the rest of its
+    // image has been discarded so that none of it reaches the next one. Dropping the sender
+    // ends the receiver.
+    let next = complete && {
+        captures.pop_front();
+        !captures.is_empty()
+    };
+
+    // Buffers are handed back to the port, and the capture is armed or disarmed, only once the
+    // queue is unlocked, so that nothing MMAL does in the meantime waits on the lock. MMAL
+    // calls this from its own callback thread rather than the one that answers parameter
+    // requests, so setting the capture parameter from here doesn't wait on itself.
+    drop(captures);
+
+    match delivery {
+        Delivery::Delivered => {}
+        Delivery::Discarded(guard) => drop(guard),
+        Delivery::Failed(guard) => {
+            drop(guard);
+            // A complete image needs no disarming, and by now a newly queued capture may
+            // have been armed in its place
+            if !complete {
+                if let Err(_err) = disarm_capture(userdata.still_port) {
+                    debug!("Unable to disarm the capture: {}", _err);
                 }
             }
-        } else {
-            let _result = match &mut userdata.sender {
-                SenderKind::AsyncSender(sender) => sender.close_channel(),
-                SenderKind::SyncSender(sender) => {
-                    if let Err(_err) = sender.send(None) {
-                        debug!("Got err sending None: {}", _err);
-                    }
-                }
-            };
         }
-    } else {
-        debug!("Received a camera still buffer callback with no state");
+    }
+
+    if complete {
+        arm_next_capture(userdata, next);
+    }
+}
+
+/// Returns whether a still buffer with `flags` ends its image, and whether the image failed.
+///
+/// The image fails if the camera marks it as unusable, or if the stream ends before the end of
+/// the frame.
+fn still_buffer_state(flags: u32) -> (bool, bool) {
+    let frame_end = flags & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END > 0;
+    let eos = flags & ffi::MMAL_BUFFER_HEADER_FLAG_EOS > 0;
+    if eos && !frame_end {
+        debug!("End of stream before the end of the image");
+    }
+
+    let failed = flags & CAPTURE_FAILED_FLAGS > 0 || (eos && !frame_end);
+    (failed || frame_end, failed)
+}
+
+/// Whether the exposure has moved from `previous` in the direction of the change in
+/// compensation from `previous_step` to `step`.
+fn exposure_moved(
+    previous_step: i32,
+    previous: &SensorSettings,
+    step: i32,
+    settings: &SensorSettings,
+) -> bool {
+    let change = settings.total_exposure() - previous.total_exposure();
+    match step.cmp(&previous_step) {
+        cmp::Ordering::Greater => change > 0.0,
+        cmp::Ordering::Less => change < 0.0,
+        cmp::Ordering::Equal => true,
+    }
+}
+
+/// Whether the camera's exposure is close to the requested shutter speed. A shutter speed of
+/// zero is automatic, so any exposure will do.
+fn shutter_reached(shutter_speed: Duration, settings: &SensorSettings) -> bool {
+    let requested = shutter_speed.as_secs_f32();
+    requested == 0.0
+        || (settings.exposure.as_secs_f32() - requested).abs() <= SHUTTER_TOLERANCE * requested
+}
+
+/// Arms the capture at the front of the queue if `next` is set. A capture that can't be armed
+/// is dropped, which disconnects its receiver, and the one after it is tried instead.
+unsafe fn arm_next_capture(userdata: &Userdata, mut next: bool) {
+    while next {
+        match arm_capture(userdata.still_port) {
+            Ok(()) => return,
+            Err(_err) => {
+                debug!("Unable to start the next capture: {}", _err);
+                let mut captures = userdata.captures.lock();
+                captures.pop_front();
+                next = !captures.is_empty();
+            }
+        }
+    }
+}
+
+/// Sets the number and size of the buffers for a port whose format has been committed,
+/// defaulting to what the port recommends.
+unsafe fn set_port_buffers(
+    port: *mut ffi::MMAL_PORT_T,
+    pool: &PoolSettings,
+) -> Result<(), CameraError> {
+    let name = CStr::from_ptr((*port).name).to_string_lossy();
+
+    let buffer_num = match pool.buffer_num {
+        Some(num) if num < (*port).buffer_num_min => {
+            return Err(ErrorKind::InvalidArgument(
+                Stage::Pool,
+                format!(
+                    "Port {} needs at least {} buffers, got {}",
+                    name,
+                    (*port).buffer_num_min,
+                    num
+                ),
+            )
+            .into());
+        }
+        Some(num) => num,
+        None => (*port).buffer_num_recommended.max((*port).buffer_num_min),
+    };
+
+    let buffer_size = match pool.buffer_size {
+        Some(size) if size < (*port).buffer_size_min => {
+            return Err(ErrorKind::InvalidArgument(
+                Stage::Pool,
+                format!(
+                    "Port {} needs buffers of at least {} bytes, got {}",
+                    name,
+                    (*port).buffer_size_min,
+                    size
+                ),
+            )
+            .into());
+        }
+        Some(size) => size,
+        None => (*port).buffer_size_recommended.max((*port).buffer_size_min),
+    };
+
+    debug!(
+        "port {} has {} buffers of {} bytes",
+        name, buffer_num, buffer_size
+    );
+    (*port).buffer_num = buffer_num;
+    (*port).buffer_size = buffer_size;
+    Ok(())
+}
+
+/// Starts capturing a still image.
+unsafe fn arm_capture(still_port: *mut ffi::MMAL_PORT_T) -> Result<(), CameraError> {
+    let status = ffi::mmal_port_parameter_set_boolean(still_port, ffi::MMAL_PARAMETER_CAPTURE, 1);
+    match status {
+        MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+        s => Err(
+            MmalError::with_status("Unable to set camera capture boolean".to_owned(), s)
+                .with_stage(Stage::Camera)
+                .into(),
+        ),
+    }
+}
+
+/// Stops capturing a still image.
+unsafe fn disarm_capture(still_port: *mut ffi::MMAL_PORT_T) -> Result<(), CameraError> {
+    let status = ffi::mmal_port_parameter_set_boolean(still_port, ffi::MMAL_PARAMETER_CAPTURE, 0);
+    match status {
+        MMAL_STATUS_T::MMAL_SUCCESS => Ok(()),
+        s => Err(
+            MmalError::with_status("Unable to disarm camera capture".to_owned(), s)
+                .with_stage(Stage::Camera)
+                .into(),
+        ),
     }
 }
 
 unsafe extern "C" fn camera_control_callback(
-    _port: *mut ffi::MMAL_PORT_T,
+    port: *mut ffi::MMAL_PORT_T,
+    buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
+) {
+    // Unwinding into C is undefined behaviour
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handle_control_buffer(port, buffer)));
+    if result.is_err() {
+        debug!("Panicked while handling a camera control buffer");
+    }
+
+    ffi::mmal_buffer_header_release(buffer);
+}
+
+unsafe fn handle_control_buffer(
+    port: *mut ffi::MMAL_PORT_T,
     buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
 ) {
     // https://github.com/raspberrypi/userland/blob/master/host_applications/linux/apps/raspicam/RaspiStillYUV.c#L525
 
     debug!("Camera control callback  cmd=0x{:08x}", (*buffer).cmd);
 
-    if (*buffer).cmd == ffi::MMAL_EVENT_PARAMETER_CHANGED {
-        let param: *mut ffi::MMAL_EVENT_PARAMETER_CHANGED_T =
-            (*buffer).data as *mut ffi::MMAL_EVENT_PARAMETER_CHANGED_T;
-        if (*param).hdr.id == (ffi::MMAL_PARAMETER_CAMERA_SETTINGS as u32) {
-            let settings_ptr: *mut ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T =
-                param as *mut ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T;
-            let _settings: ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T = *settings_ptr;
+    let event = CameraEvent::from_buffer(buffer);
+    match &event {
+        Some(CameraEvent::SettingsChanged(settings)) => {
             debug!(
-                "Exposure now {}, analog gain {}/{}, digital gain {}/{}",
-                _settings.exposure,
-                _settings.analog_gain.num,
-                _settings.analog_gain.den,
-                _settings.digital_gain.num,
-                _settings.digital_gain.den
+                "Exposure now {:?}, analog gain {}, digital gain {}, AWB R={}, B={}",
+                settings.exposure,
+                settings.analog_gain,
+                settings.digital_gain,
+                settings.awb_red_gain,
+                settings.awb_blue_gain
             );
+        }
+        Some(CameraEvent::Error) => {
             debug!(
-                "AWB R={}/{}, B={}/{}",
-                _settings.awb_red_gain.num,
-                _settings.awb_red_gain.den,
-                _settings.awb_blue_gain.num,
-                _settings.awb_blue_gain.den
+                "No data received from sensor. Check all connections, including the Sunny one on the camera board"
             );
         }
-    } else if (*buffer).cmd == ffi::MMAL_EVENT_ERROR {
-        debug!(
-            "No data received from sensor. Check all connections, including the Sunny one on the camera board"
-        );
-    } else {
-        debug!(
-            "Received unexpected camera control callback event, {:08x}",
-            (*buffer).cmd
-        );
+        Some(CameraEvent::Unknown(_)) => {
+            debug!(
+                "Received unexpected camera control callback event, {:08x}",
+                (*buffer).cmd
+            );
+        }
+        None => {}
     }
 
-    ffi::mmal_buffer_header_release(buffer);
+    let state = (*port).userdata as *const ControlState;
+    if let Some(event) = event.filter(|_| !state.is_null()) {
+        (*state).publish(event);
+    }
 }
 
 impl Drop for SeriousCamera {
     fn drop(&mut self) {
         unsafe {
-            let _guard = self.mutex.lock();
+            if let Err(_err) = self.stop_captures() {
+                debug!("Unable to stop captures: {}", _err);
+            }
 
+            // Its connections need the encoder, which is disabled below
+            self.still_resizer = None;
             if self.connection_created {
                 ffi::mmal_connection_disable(self.connection.unwrap().as_ptr());
                 ffi::mmal_connection_destroy(self.connection.unwrap().as_ptr());
@@ -1074,7 +2468,12 @@ impl Drop for SeriousCamera {
                 debug!("encoder control port disabled");
             }
             if self.camera_port_enabled {
-                ffi::mmal_port_disable(self.camera.as_ref().control);
+                let control = self.camera.as_ref().control;
+                ffi::mmal_port_disable(control);
+                if !(*control).userdata.is_null() {
+                    drop(Arc::from_raw((*control).userdata as *const ControlState));
+                    (*control).userdata = ptr::null_mut();
+                }
                 debug!("camera port disabled");
             }
             if self.still_port_enabled {
@@ -1091,19 +2490,9 @@ impl Drop for SeriousCamera {
                 ffi::mmal_component_destroy(self.preview.unwrap().as_ptr());
                 debug!("preview destroyed");
             }
-            if self.pool.is_some() {
-                let port_ptr = if self.use_encoder {
-                    let output = self.encoder.unwrap().as_ref().output;
-                    *(output.offset(0) as *mut *mut ffi::MMAL_PORT_T)
-                } else {
-                    let output = self.camera.as_ref().output;
-                    *(output.offset(MMAL_CAMERA_CAPTURE_PORT) as *mut *mut ffi::MMAL_PORT_T)
-                };
-                // port doesn't need to be disabled because it is already
-                // previously disabled in previous if statements
-                ffi::mmal_port_pool_destroy(port_ptr, self.pool.unwrap().as_ptr());
-                debug!("pool destroyed");
-            }
+            // The pool is destroyed now unless there are still buffer guards around. The
+            // components it belongs to aren't destroyed by MMAL until it has been.
+            self.pool = None;
 
             ffi::mmal_component_destroy(self.camera.as_ptr());
             debug!("camera destroyed");
@@ -1123,14 +2512,13 @@ impl Drop for SeriousCamera {
 /// use rascam::SimpleCamera;
 /// use std::fs::File;
 /// use std::io::Write;
-/// use std::{thread, time};
+/// use std::time::Duration;
 ///
 /// let info = rascam::info().unwrap();
 /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
 /// camera.activate().unwrap();
 ///
-/// let sleep_duration = time::Duration::from_millis(2000);
-/// thread::sleep(sleep_duration);
+/// camera.wait_until_settled(Duration::from_secs(5)).unwrap();
 ///
 /// let b = camera.take_one().unwrap();
 /// File::create("image1.jpg").unwrap().write_all(&b).unwrap();
@@ -1139,6 +2527,9 @@ pub struct SimpleCamera {
     info: CameraInfo,
     serious: SeriousCamera,
     settings: Option<CameraSettings>,
+    capture_timeout: Duration,
+    white_balance_locked: bool,
+    check_jpeg: bool,
 }
 
 impl SimpleCamera {
@@ -1149,9 +2540,33 @@ impl SimpleCamera {
             info,
             serious: sc,
             settings: None,
+            capture_timeout: DEFAULT_CAPTURE_TIMEOUT,
+            white_balance_locked: false,
+            check_jpeg: false,
         })
     }
 
+    /// Sets how long to wait for each image before giving up with an [`ErrorKind::Timeout`]
+    /// error. Defaults to 30 seconds.
+    ///
+    /// Long exposures take several times the shutter speed to capture so may need a longer
+    /// timeout.
+    pub fn set_capture_timeout(&mut self, timeout: Duration) {
+        self.capture_timeout = timeout;
+    }
+
+    pub fn capture_timeout(&self) -> Duration {
+        self.capture_timeout
+    }
+
+    /// Sets whether to check that JPEG images start and end with the right markers, to catch
+    /// truncated images that the camera didn't flag as failed. Off by default.
+    ///
+    /// Images that fail the check are reported as [`ErrorKind::CaptureFailed`].
+    pub fn set_check_jpeg(&mut self, check: bool) {
+        self.check_jpeg = check;
+    }
+
     pub fn configure(&mut self, mut settings: CameraSettings) {
         if settings.width == 0 {
             settings.width = self.info.max_width;
@@ -1169,6 +2584,7 @@ impl SimpleCamera {
         }
         let settings = self.settings.as_ref().unwrap();
         let camera = &mut self.serious;
+        self.white_balance_locked = settings.awb_gains.is_some();
 
         camera.set_camera_num(0)?;
         camera.create_encoder()?;
@@ -1196,22 +2612,89 @@ impl SimpleCamera {
     ///
     /// If there is an error
     pub fn take_one_writer(&mut self, writer: &mut dyn Write) -> Result<(), CameraError> {
-        let receiver = self.serious.take()?;
+        self.receive_image(|buf| Ok(writer.write_all(buf.get_bytes())?))
+    }
+
+    /// Starts a capture and passes each buffer of the image to `on_buffer`.
+    ///
+    /// The capture is cancelled if it times out or `on_buffer` fails.
+    fn receive_image<F>(&mut self, mut on_buffer: F) -> Result<(), CameraError>
+    where
+        F: FnMut(&BufferGuard) -> Result<(), CameraError>,
+    {
+        let deadline = Instant::now() + self.capture_timeout;
+        let stage = self.serious.capture_stage();
+        let mut jpeg = self.jpeg_markers();
+        let (receiver, id) = match self.serious.queue_capture(false)? {
+            (ReceiverKind::SyncReceiver(receiver), id) => (receiver, id),
+            (ReceiverKind::AsyncReceiver(_), _) => unreachable!(),
+        };
+        // Cancels the capture however this finishes, including by panicking
+        let capture = scopeguard::guard((receiver, id, &mut self.serious), cancel_capture);
 
         loop {
-            let result = receiver.recv()?;
-            match result {
-                Some(buf) => {
-                    writer.write_all(buf.get_bytes())?;
-                    if buf.is_complete() {
-                        break;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match capture.0.recv_timeout(remaining) {
+                Ok(Some(buf)) => {
+                    let complete = check_buffer(&buf, stage, &mut jpeg)?;
+                    on_buffer(&buf)?;
+                    if complete {
+                        return Ok(());
                     }
                 }
-                None => break,
-            };
+                Ok(None) => return Err(ErrorKind::CaptureFailed(stage).into()),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(ErrorKind::Timeout(stage).into())
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(ErrorKind::CaptureFailed(stage).into())
+                }
+            }
         }
+    }
 
-        Ok(())
+    /// Starts a capture and passes each buffer of the image to `on_buffer`.
+    ///
+    /// The capture is cancelled if it times out, `on_buffer` fails or the future is dropped.
+    async fn receive_image_async<F>(&mut self, mut on_buffer: F) -> Result<(), CameraError>
+    where
+        F: FnMut(&BufferGuard) -> Result<(), CameraError>,
+    {
+        let mut deadline = delay(self.capture_timeout);
+        let stage = self.serious.capture_stage();
+        let mut jpeg = self.jpeg_markers();
+        let (receiver, id) = match self.serious.queue_capture(true)? {
+            (ReceiverKind::AsyncReceiver(receiver), id) => (receiver, id),
+            (ReceiverKind::SyncReceiver(_), _) => unreachable!(),
+        };
+        let mut capture = scopeguard::guard((receiver, id, &mut self.serious), cancel_capture);
+
+        loop {
+            match future::select(capture.0.next(), &mut deadline).await {
+                Either::Left((Some(buf), _)) => {
+                    let complete = check_buffer(&buf, stage, &mut jpeg)?;
+                    on_buffer(&buf)?;
+                    if complete {
+                        return Ok(());
+                    }
+                }
+                Either::Left((None, _)) => return Err(ErrorKind::CaptureFailed(stage).into()),
+                Either::Right(_) => return Err(ErrorKind::Timeout(stage).into()),
+            }
+        }
+    }
+
+    /// Returns a checker for the JPEG markers if checking is turned on and the camera is
+    /// producing JPEGs.
+    fn jpeg_markers(&self) -> Option<JpegMarkers> {
+        let jpeg = self.settings.as_ref().map_or(false, |settings| {
+            settings.encoding == ffi::MMAL_ENCODING_JPEG
+        });
+        if self.check_jpeg && jpeg {
+            Some(JpegMarkers::default())
+        } else {
+            None
+        }
     }
 
     /// Captures a single image from the camera synchronously.
@@ -1223,21 +2706,464 @@ impl SimpleCamera {
         Ok(v)
     }
 
+    /// Captures a single image along with a copy scaled by the hardware resizer, e.g. a full
+    /// resolution JPEG and a small RGB frame to analyse.
+    ///
+    /// Set [`CameraSettings::still_resizer`] before activating the camera, otherwise this fails
+    /// with [`ErrorKind::InvalidState`].
+    ///
+    /// ```
+    /// use rascam::{CameraSettings, PixelFormat, ResizerSettings, SimpleCamera};
+    ///
+    /// let info = rascam::info().unwrap();
+    /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+    /// camera.configure(CameraSettings {
+    ///     still_resizer: Some(ResizerSettings {
+    ///         format: PixelFormat::Rgb24,
+    ///         width: 640,
+    ///         height: 480,
+    ///         ..ResizerSettings::default()
+    ///     }),
+    ///     ..CameraSettings::default()
+    /// });
+    /// camera.activate().unwrap();
+    ///
+    /// let (jpeg, thumbnail) = camera.take_one_resized().unwrap();
+    /// assert_eq!(thumbnail.width(), 640);
+    /// ```
+    pub fn take_one_resized(&mut self) -> Result<(Vec<u8>, Frame), CameraError> {
+        if self.serious.still_resizer.is_none() {
+            return Err(ErrorKind::InvalidState(Stage::Resizer).into());
+        }
+
+        // Frames from earlier captures whose scaled copies weren't received
+        self.serious.discard_resized_stills();
+        let image = self.take_one()?;
+        let frame = self
+            .serious
+            .next_resized_still(Some(self.capture_timeout))?;
+        Ok((image, frame))
+    }
+
+    /// Waits until auto exposure and white balance have settled, so that an image can be
+    /// taken as soon as it will come out well.
+    ///
+    /// The camera must be activated first. Returns the settings the camera settled on, or an
+    /// [`ErrorKind::Timeout`] error if they were still changing after `timeout`.
+    ///
+    /// ```
+    /// use rascam::SimpleCamera;
+    /// use std::time::Duration;
+    ///
+    /// let info = rascam::info().unwrap();
+    /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+    /// camera.activate().unwrap();
+    /// camera.wait_until_settled(Duration::from_secs(5)).unwrap();
+    ///
+    /// let b = camera.take_one().unwrap();
+    /// ```
+    pub fn wait_until_settled(&mut self, timeout: Duration) -> Result<SensorSettings, CameraError> {
+        futures::executor::block_on(self.wait_until_settled_async(timeout))
+    }
+
+    /// Waits asynchronously until auto exposure and white balance have settled.
+    ///
+    /// See [`wait_until_settled`](SimpleCamera::wait_until_settled).
+    pub async fn wait_until_settled_async(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<SensorSettings, CameraError> {
+        self.wait_for_settings(timeout, |_| true).await
+    }
+
+    /// Waits until the settings have settled on values that `accept` returns `true` for.
+    async fn wait_for_settings<A>(
+        &self,
+        timeout: Duration,
+        mut accept: A,
+    ) -> Result<SensorSettings, CameraError>
+    where
+        A: FnMut(&SensorSettings) -> bool,
+    {
+        let mut events = self.serious.events();
+        let mut deadline = delay(timeout);
+        let mut tracker = SettleTracker::default();
+
+        loop {
+            match future::select(events.next(), &mut deadline).await {
+                Either::Left((Some(CameraEvent::SettingsChanged(settings)), _)) => {
+                    if tracker.push(settings) && accept(&settings) {
+                        return Ok(settings);
+                    }
+                }
+                Either::Left((Some(_), _)) => {}
+                Either::Left((None, _)) | Either::Right(_) => {
+                    return Err(ErrorKind::Timeout(Stage::Camera).into());
+                }
+            }
+        }
+    }
+
+    /// Fixes the shutter speed and gains at the values the camera is currently using, so that
+    /// the brightness of a series of images doesn't drift.
+    ///
+    /// Wait for the exposure to settle first, e.g. with
+    /// [`wait_until_settled`](SimpleCamera::wait_until_settled). Returns the settings that were
+    /// locked, or [`ErrorKind::InvalidState`] if the camera hasn't reported any yet.
+    pub fn lock_exposure(&mut self) -> Result<SensorSettings, CameraError> {
+        let settings = self.reported_settings()?;
+
+        self.serious
+            .set_shutter_speed(settings.exposure.as_micros() as u32)?;
+        self.serious
+            .set_gains(settings.analog_gain, settings.digital_gain)?;
+        self.serious
+            .set_exposure_mode(ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_OFF)?;
+
+        Ok(settings)
+    }
+
+    /// Returns to automatic exposure after [`lock_exposure`](SimpleCamera::lock_exposure).
+    ///
+    /// The shutter speed and both gains are reset to zero, which leaves them to the camera as
+    /// they were before the exposure was locked.
+    pub fn unlock_exposure(&mut self) -> Result<(), CameraError> {
+        self.serious
+            .set_exposure_mode(ffi::MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_AUTO)?;
+        self.serious.set_shutter_speed(0)?;
+        self.serious.set_gains(0.0, 0.0)
+    }
+
+    /// Fixes the white balance at the red and blue gains the camera is currently using, so
+    /// that the colour of a series of images doesn't drift.
+    ///
+    /// Wait for the white balance to settle first, e.g. with
+    /// [`wait_until_settled`](SimpleCamera::wait_until_settled). Returns the settings that were
+    /// locked, or [`ErrorKind::InvalidState`] if the camera hasn't reported any yet.
+    pub fn lock_white_balance(&mut self) -> Result<SensorSettings, CameraError> {
+        let settings = self.reported_settings()?;
+
+        self.serious
+            .set_awb_mode(ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_OFF)?;
+        self.serious
+            .set_awb_gains(settings.awb_red_gain, settings.awb_blue_gain)?;
+        self.white_balance_locked = true;
+
+        Ok(settings)
+    }
+
+    /// Returns to automatic white balance after
+    /// [`lock_white_balance`](SimpleCamera::lock_white_balance).
+    pub fn unlock_white_balance(&mut self) -> Result<(), CameraError> {
+        self.serious
+            .set_awb_mode(ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_AUTO)?;
+        self.white_balance_locked = false;
+        Ok(())
+    }
+
+    /// The settings the camera last reported, or an error if it hasn't reported any yet.
+    fn reported_settings(&self) -> Result<SensorSettings, CameraError> {
+        self.serious
+            .sensor_settings()
+            .ok_or_else(|| ErrorKind::InvalidState(Stage::Camera).into())
+    }
+
+    /// Captures a single image along with the settings the camera used for it.
+    pub fn take_one_with_metadata(&mut self) -> Result<(Vec<u8>, CaptureMetadata), CameraError> {
+        let mut v = Vec::new();
+        // The settings may change while the rest of the image arrives, so take them along with
+        // the first buffer
+        let control = Arc::clone(&self.serious.control);
+        let mut started = None;
+        let mut pts = None;
+        self.receive_image(|buf| {
+            started.get_or_insert_with(|| (SystemTime::now(), *control.sensor.lock()));
+            pts = pts.or_else(|| buf.pts());
+            v.extend_from_slice(buf.get_bytes());
+            Ok(())
+        })?;
+
+        let (captured_at, sensor) =
+            started.unwrap_or_else(|| (SystemTime::now(), self.serious.sensor_settings()));
+        let metadata = CaptureMetadata {
+            sensor,
+            pts,
+            captured_at,
+        };
+        Ok((v, metadata))
+    }
+
+    /// Captures a series of images with different exposure compensations, e.g. for
+    /// [`fuse_exposures`].
+    ///
+    /// `ev_offsets` are in stops and are rounded to the nearest sixth of a stop. The camera
+    /// supports offsets from -4 1/6 to +4 1/6 stops. White balance is locked for the series so
+    /// that the images match. Afterwards the exposure compensation is restored and automatic
+    /// white balance is turned back on.
+    ///
+    /// Before each image, this waits for the camera to report a settled exposure that has
+    /// moved in the direction of the new compensation. If that doesn't happen within five
+    /// seconds, e.g. because the camera is already at its longest exposure, the bracket fails
+    /// with [`ErrorKind::Timeout`].
+    ///
+    /// ```
+    /// use rascam::SimpleCamera;
+    ///
+    /// let info = rascam::info().unwrap();
+    /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+    /// camera.activate().unwrap();
+    ///
+    /// let images = camera.take_bracket(&[-2.0, 0.0, 2.0]).unwrap();
+    /// assert_eq!(images.len(), 3);
+    /// ```
+    pub fn take_bracket(&mut self, ev_offsets: &[f32]) -> Result<Vec<Vec<u8>>, CameraError> {
+        let mut steps = Vec::with_capacity(ev_offsets.len());
+        for &ev in ev_offsets {
+            let step = (ev * 6.0).round();
+            if !(-25.0..=25.0).contains(&step) {
+                return Err(ErrorKind::InvalidArgument(
+                    Stage::Camera,
+                    format!("Exposure compensation of {} stops is out of range", ev),
+                )
+                .into());
+            }
+            steps.push(step as i32);
+        }
+
+        let original = self.serious.exposure_compensation()?;
+        // The compensation and settings of the previous image, to compare each new one with
+        let mut previous = (original, self.serious.sensor_settings());
+        let result = self.take_series(
+            steps.len(),
+            |camera, i| camera.set_exposure_compensation(steps[i]),
+            |i, settings| {
+                let (previous_step, previous_settings) = previous;
+                let reached = previous_settings.map_or(true, |previous_settings| {
+                    exposure_moved(previous_step, &previous_settings, steps[i], settings)
+                });
+                if reached {
+                    previous = (steps[i], Some(*settings));
+                }
+                reached
+            },
+        );
+        let restored = self.serious.set_exposure_compensation(original);
+
+        let images = result?;
+        restored?;
+        Ok(images)
+    }
+
+    /// Captures a series of images with manual shutter speeds.
+    ///
+    /// As with [`take_bracket`], white balance is locked for the series. Afterwards the shutter
+    /// speed is restored.
+    ///
+    /// Before each image, this waits for the camera to report a settled exposure within 10% of
+    /// the shutter speed, failing with [`ErrorKind::Timeout`] if it doesn't within five
+    /// seconds. A shutter speed of zero is automatic and takes whatever the camera settles on.
+    ///
+    /// [`take_bracket`]: SimpleCamera::take_bracket
+    pub fn take_bracket_shutter(
+        &mut self,
+        shutter_speeds: &[Duration],
+    ) -> Result<Vec<Vec<u8>>, CameraError> {
+        let original = self.serious.shutter_speed()?;
+        let result = self.take_series(
+            shutter_speeds.len(),
+            |camera, i| camera.set_shutter_speed(shutter_speeds[i].as_micros() as u32),
+            |i, settings| shutter_reached(shutter_speeds[i], settings),
+        );
+        let restored = self.serious.set_shutter_speed(original);
+
+        let images = result?;
+        restored?;
+        Ok(images)
+    }
+
+    /// Takes `count` images with white balance locked, calling `setup` before each one.
+    ///
+    /// Each image waits for the settings to settle on values that `reached` accepts for it.
+    /// If the white balance was already locked with
+    /// [`lock_white_balance`](SimpleCamera::lock_white_balance), it stays locked afterwards.
+    fn take_series<F, R>(
+        &mut self,
+        count: usize,
+        mut setup: F,
+        mut reached: R,
+    ) -> Result<Vec<Vec<u8>>, CameraError>
+    where
+        F: FnMut(&mut SeriousCamera, usize) -> Result<(), CameraError>,
+        R: FnMut(usize, &SensorSettings) -> bool,
+    {
+        let was_locked = self.white_balance_locked;
+        if !was_locked {
+            self.serious
+                .set_awb_mode(ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_OFF)?;
+        }
+
+        let mut images = Vec::with_capacity(count);
+        let mut result = Ok(());
+        for i in 0..count {
+            result = setup(&mut self.serious, i);
+            if result.is_err() {
+                break;
+            }
+
+            let settled = futures::executor::block_on(
+                self.wait_for_settings(BRACKET_SETTLE_TIMEOUT, |settings| reached(i, settings)),
+            );
+            if let Err(err) = settled {
+                result = Err(err);
+                break;
+            }
+
+            match self.take_one() {
+                Ok(image) => images.push(image),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        let unlocked = if was_locked {
+            Ok(())
+        } else {
+            self.serious
+                .set_awb_mode(ffi::MMAL_PARAM_AWBMODE_T_MMAL_PARAM_AWBMODE_AUTO)
+        };
+
+        result?;
+        unlocked?;
+        Ok(images)
+    }
+
     /// Captures a single image from the camera asynchronously.
     ///
     /// Returns a future result where `Ok` contains a `Vec<u8>` containing the bytes of the image.
     pub async fn take_one_async(&mut self) -> Result<Vec<u8>, CameraError> {
-        let receiver = self.serious.take_async()?;
-        let future = receiver
-            .fold(Vec::new(), |mut acc, buf| {
-                async move {
-                    acc.extend(buf.get_bytes());
-                    acc
-                }
-            })
-            .map(Ok);
+        let mut v = Vec::new();
+        self.receive_image_async(|buf| {
+            v.extend_from_slice(buf.get_bytes());
+            Ok(())
+        })
+        .await?;
+        Ok(v)
+    }
 
-        future.await
+    /// Captures a single image asynchronously along with the settings the camera used for it.
+    pub async fn take_one_with_metadata_async(
+        &mut self,
+    ) -> Result<(Vec<u8>, CaptureMetadata), CameraError> {
+        let mut v = Vec::new();
+        // Snapshot the settings at the first buffer, as take_one_with_metadata does
+        let control = Arc::clone(&self.serious.control);
+        let mut started = None;
+        let mut pts = None;
+        self.receive_image_async(|buf| {
+            started.get_or_insert_with(|| (SystemTime::now(), *control.sensor.lock()));
+            pts = pts.or_else(|| buf.pts());
+            v.extend_from_slice(buf.get_bytes());
+            Ok(())
+        })
+        .await?;
+
+        let (captured_at, sensor) =
+            started.unwrap_or_else(|| (SystemTime::now(), self.serious.sensor_settings()));
+        let metadata = CaptureMetadata {
+            sensor,
+            pts,
+            captured_at,
+        };
+        Ok((v, metadata))
+    }
+
+    /// Continuously captures raw frames from the camera's video port.
+    ///
+    /// The camera must be activated first. Capturing stops when the returned stream is dropped.
+    ///
+    /// ```
+    /// use rascam::{PixelFormat, SimpleCamera, StreamSettings};
+    ///
+    /// let info = rascam::info().unwrap();
+    /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+    /// camera.activate().unwrap();
+    ///
+    /// let settings = StreamSettings {
+    ///     format: PixelFormat::Rgb24,
+    ///     ..StreamSettings::default()
+    /// };
+    /// for frame in camera.stream(settings).unwrap().take(10) {
+    ///     let frame = frame.unwrap().to_packed();
+    ///     assert_eq!(frame.data().len(), 640 * 480 * 3);
+    /// }
+    /// ```
+    pub fn stream(&mut self, settings: StreamSettings) -> Result<FrameStream<'_>, CameraError> {
+        self.serious.stream(&settings)
+    }
+
+    /// Sends the video port to several outputs at once, e.g. to record H.264 while analysing
+    /// raw frames.
+    ///
+    /// The camera must be activated first. See [`SeriousCamera::split`].
+    ///
+    /// ```
+    /// use rascam::{EncoderSettings, PixelFormat, SimpleCamera, SplitterOutput, VideoSettings};
+    ///
+    /// let info = rascam::info().unwrap();
+    /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+    /// camera.activate().unwrap();
+    ///
+    /// let outputs = [
+    ///     SplitterOutput::Encoder(EncoderSettings::default()),
+    ///     SplitterOutput::Raw { format: PixelFormat::I420, buffers: 3 },
+    /// ];
+    /// let mut streams = camera.split(VideoSettings::default(), &outputs).unwrap();
+    /// let frames = streams.pop().unwrap().into_frames().unwrap();
+    /// let video = streams.pop().unwrap().into_video().unwrap();
+    /// ```
+    pub fn split(
+        &mut self,
+        settings: VideoSettings,
+        outputs: &[SplitterOutput],
+    ) -> Result<Vec<SplitterStream<'_>>, CameraError> {
+        self.serious.split(&settings, outputs)
+    }
+
+    /// Streams scaled frames from the video port, leaving the still port free.
+    ///
+    /// The camera must be activated first. See [`SeriousCamera::resize`], and
+    /// [`take_one_resized`](SimpleCamera::take_one_resized) to scale still images instead.
+    ///
+    /// ```
+    /// use rascam::{PixelFormat, ResizerSettings, SimpleCamera, VideoSettings};
+    ///
+    /// let info = rascam::info().unwrap();
+    /// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+    /// camera.activate().unwrap();
+    ///
+    /// let video = VideoSettings {
+    ///     width: info.cameras[0].max_width,
+    ///     height: info.cameras[0].max_height,
+    ///     frame_rate: 10,
+    /// };
+    /// let resizer = ResizerSettings {
+    ///     format: PixelFormat::Rgb24,
+    ///     width: 640,
+    ///     height: 480,
+    ///     ..ResizerSettings::default()
+    /// };
+    /// let mut preview = camera.resize(video, resizer).unwrap();
+    /// let frame = preview.next_frame().unwrap();
+    /// assert_eq!(frame.width(), 640);
+    /// ```
+    pub fn resize(
+        &mut self,
+        settings: VideoSettings,
+        resizer: ResizerSettings,
+    ) -> Result<FrameStream<'_>, CameraError> {
+        self.serious.resize(&settings, &resizer)
     }
 }
 
@@ -1247,18 +3173,127 @@ impl SimpleCamera {
 ///
 /// `port.userdata` must be non-null or this will dereference a null pointer.
 pub unsafe fn drop_port_userdata(port: *mut ffi::MMAL_PORT_T) {
-    let userdata: Box<Userdata> = Box::from_raw((*port).userdata as *mut Userdata);
-    userdata._guard.force_unlock();
-    drop(userdata);
+    drop(Box::from_raw((*port).userdata as *mut Userdata));
     (*port).userdata = ptr::null_mut() as *mut ffi::MMAL_PORT_USERDATA_T;
 }
 
-trait Sender {
-    fn try_send(&mut self, msg: BufferGuard);
+#[test]
+fn test_thread_safe_types() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+
+    assert_send_sync::<SeriousCamera>();
+    assert_send_sync::<SimpleCamera>();
+    assert_send_sync::<BufferGuard>();
+    assert_send::<mpsc::Receiver<Option<BufferGuard>>>();
+    assert_send::<futures::channel::mpsc::Receiver<BufferGuard>>();
+    assert_send::<FrameStream>();
+    assert_send::<VideoStream>();
+    assert_send_sync::<Frame>();
 }
 
-impl Sender for futures::channel::mpsc::Sender<BufferGuard> {
-    fn try_send(&mut self, msg: BufferGuard) {
-        futures::channel::mpsc::Sender::try_send(self, msg).unwrap()
+#[test]
+fn test_capture_ticket() {
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    let first = CaptureTicket::new(&in_flight);
+    let second = CaptureTicket::new(&in_flight);
+    assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+
+    // A capture stays in flight while anything holds on to its ticket
+    let held = Arc::clone(&first);
+    drop(first);
+    assert_eq!(in_flight.load(Ordering::SeqCst), 2);
+    drop(held);
+    assert_eq!(in_flight.load(Ordering::SeqCst), 1);
+    drop(second);
+    assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_set_port_buffers() {
+    let mut port: ffi::MMAL_PORT_T = unsafe { mem::zeroed() };
+    port.name = b"vc.ril.camera:out:2\0".as_ptr() as *const c_char;
+    port.buffer_num_min = 1;
+    port.buffer_num_recommended = 3;
+    port.buffer_size_min = 1000;
+    port.buffer_size_recommended = 4000;
+
+    unsafe {
+        set_port_buffers(&mut port, &PoolSettings::default()).unwrap();
+        assert_eq!((port.buffer_num, port.buffer_size), (3, 4000));
+
+        let pool = PoolSettings {
+            buffer_num: Some(1),
+            buffer_size: Some(1000),
+            ..PoolSettings::default()
+        };
+        set_port_buffers(&mut port, &pool).unwrap();
+        assert_eq!((port.buffer_num, port.buffer_size), (1, 1000));
+
+        for pool in [
+            PoolSettings {
+                buffer_num: Some(0),
+                ..PoolSettings::default()
+            },
+            PoolSettings {
+                buffer_size: Some(999),
+                ..PoolSettings::default()
+            },
+        ]
+        .iter()
+        {
+            let err = set_port_buffers(&mut port, pool).unwrap_err();
+            match err.kind() {
+                ErrorKind::InvalidArgument(Stage::Pool, _) => {}
+                kind => panic!("unexpected error {:?}", kind),
+            }
+        }
+        // A failed check leaves the port alone
+        assert_eq!((port.buffer_num, port.buffer_size), (1, 1000));
     }
 }
+
+#[test]
+fn test_still_buffer_state() {
+    let frame_end = ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END;
+    let eos = ffi::MMAL_BUFFER_HEADER_FLAG_EOS;
+
+    assert_eq!(still_buffer_state(0), (false, false));
+    assert_eq!(still_buffer_state(frame_end), (true, false));
+    assert_eq!(still_buffer_state(frame_end | eos), (true, false));
+    // The stream ended before the image did
+    assert_eq!(still_buffer_state(eos), (true, true));
+    assert_eq!(
+        still_buffer_state(frame_end | ffi::MMAL_BUFFER_HEADER_FLAG_CORRUPTED),
+        (true, true)
+    );
+}
+
+#[test]
+fn test_bracket_exposure_checks() {
+    let settings = |exposure_us, analog_gain| SensorSettings {
+        exposure: Duration::from_micros(exposure_us),
+        analog_gain,
+        digital_gain: 1.0,
+        awb_red_gain: 1.5,
+        awb_blue_gain: 1.5,
+    };
+    let previous = settings(10_000, 2.0);
+
+    // A gain change counts as much as a change in exposure time
+    assert!(exposure_moved(0, &previous, 6, &settings(10_000, 4.0)));
+    assert!(!exposure_moved(0, &previous, 6, &settings(20_000, 1.0)));
+    assert!(!exposure_moved(0, &previous, 6, &settings(10_000, 1.0)));
+    assert!(exposure_moved(0, &previous, -6, &settings(5_000, 2.0)));
+    assert!(exposure_moved(0, &previous, 0, &previous));
+
+    let ten_ms = Duration::from_millis(10);
+    assert!(shutter_reached(ten_ms, &settings(10_000, 1.0)));
+    assert!(shutter_reached(ten_ms, &settings(10_500, 1.0)));
+    assert!(!shutter_reached(ten_ms, &settings(20_000, 1.0)));
+    assert!(shutter_reached(
+        Duration::from_secs(0),
+        &settings(20_000, 1.0)
+    ));
+}
@@ -0,0 +1,240 @@
+use futures::channel::mpsc;
+use mmal_sys as ffi;
+use parking_lot::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The exposure and white balance the camera is using, as reported by the camera.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorSettings {
+    pub exposure: Duration,
+    pub analog_gain: f32,
+    pub digital_gain: f32,
+    pub awb_red_gain: f32,
+    pub awb_blue_gain: f32,
+}
+
+impl From<&ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T> for SensorSettings {
+    fn from(settings: &ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T) -> Self {
+        SensorSettings {
+            exposure: Duration::from_micros(settings.exposure as u64),
+            analog_gain: rational(settings.analog_gain),
+            digital_gain: rational(settings.digital_gain),
+            awb_red_gain: rational(settings.awb_red_gain),
+            awb_blue_gain: rational(settings.awb_blue_gain),
+        }
+    }
+}
+
+impl SensorSettings {
+    /// The exposure time scaled by the gains, in seconds. Doubles for each extra stop of
+    /// exposure however the camera achieves it.
+    pub(crate) fn total_exposure(&self) -> f32 {
+        self.exposure.as_secs_f32() * self.analog_gain * self.digital_gain
+    }
+}
+
+fn rational(value: ffi::MMAL_RATIONAL_T) -> f32 {
+    if value.den == 0 {
+        0.0
+    } else {
+        value.num as f32 / value.den as f32
+    }
+}
+
+/// Converts a gain to the fixed point rational the camera expects.
+pub(crate) fn to_rational(value: f32) -> ffi::MMAL_RATIONAL_T {
+    ffi::MMAL_RATIONAL_T {
+        num: (value * 65536.0).round() as i32,
+        den: 65536,
+    }
+}
+
+/// Information about how an image was captured.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureMetadata {
+    /// The most recent settings reported by the camera when the image started arriving.
+    ///
+    /// `None` if the camera hadn't reported any yet.
+    pub sensor: Option<SensorSettings>,
+    /// Presentation timestamp of the image in microseconds, if the camera provided one.
+    pub pts: Option<i64>,
+    /// When the image started arriving from the camera.
+    pub captured_at: SystemTime,
+}
+
+/// Something the camera reported on its control port.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CameraEvent {
+    /// The camera changed its exposure or white balance.
+    SettingsChanged(SensorSettings),
+    /// No data was received from the sensor. This usually means a cable is loose.
+    Error,
+    /// An event this library doesn't understand, identified by its FourCC.
+    Unknown([u8; 4]),
+}
+
+impl CameraEvent {
+    /// Decodes an event buffer from the control port.
+    ///
+    /// Returns `None` for parameter changes other than the camera settings.
+    pub(crate) unsafe fn from_buffer(buffer: *const ffi::MMAL_BUFFER_HEADER_T) -> Option<Self> {
+        let cmd = (*buffer).cmd;
+        if cmd == ffi::MMAL_EVENT_PARAMETER_CHANGED {
+            let param = (*buffer).data as *const ffi::MMAL_EVENT_PARAMETER_CHANGED_T;
+            if (*param).hdr.id != ffi::MMAL_PARAMETER_CAMERA_SETTINGS {
+                return None;
+            }
+            let settings = &*(param as *const ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T);
+            Some(CameraEvent::SettingsChanged(SensorSettings::from(settings)))
+        } else if cmd == ffi::MMAL_EVENT_ERROR {
+            Some(CameraEvent::Error)
+        } else {
+            Some(CameraEvent::Unknown(cmd.to_le_bytes()))
+        }
+    }
+}
+
+/// Number of consecutive reports that must agree before the camera is considered settled.
+const SETTLE_REPORTS: usize = 5;
+/// How far apart, relative to each other, settings may be and still count as agreeing.
+const SETTLE_TOLERANCE: f32 = 0.05;
+
+/// Watches the settings the camera reports to tell when auto exposure and white balance have
+/// converged.
+#[derive(Default)]
+pub(crate) struct SettleTracker {
+    recent: Vec<SensorSettings>,
+}
+
+impl SettleTracker {
+    /// Records a report and returns whether the last few reports were all close together.
+    pub(crate) fn push(&mut self, settings: SensorSettings) -> bool {
+        if self.recent.len() == SETTLE_REPORTS {
+            self.recent.remove(0);
+        }
+        self.recent.push(settings);
+
+        self.recent.len() == SETTLE_REPORTS
+            && self.recent.iter().all(|s| {
+                close(s.exposure.as_secs_f32(), settings.exposure.as_secs_f32())
+                    && close(s.analog_gain, settings.analog_gain)
+                    && close(s.digital_gain, settings.digital_gain)
+                    && close(s.awb_red_gain, settings.awb_red_gain)
+                    && close(s.awb_blue_gain, settings.awb_blue_gain)
+            })
+    }
+}
+
+fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() <= SETTLE_TOLERANCE * a.abs().max(b.abs())
+}
+
+/// Number of events buffered for each subscriber before further events are dropped.
+const EVENT_CHANNEL_SIZE: usize = 32;
+
+/// State shared with the camera's control port callback.
+#[derive(Default)]
+pub(crate) struct ControlState {
+    pub(crate) sensor: Mutex<Option<SensorSettings>>,
+    subscribers: Mutex<Vec<mpsc::Sender<CameraEvent>>>,
+}
+
+impl ControlState {
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<CameraEvent> {
+        let (sender, receiver) = mpsc::channel(EVENT_CHANNEL_SIZE);
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    /// Records an event and passes it on to every subscriber.
+    ///
+    /// Subscribers that have gone away are forgotten. Subscribers that aren't keeping up miss
+    /// the event rather than holding up the camera.
+    pub(crate) fn publish(&self, event: CameraEvent) {
+        if let CameraEvent::SettingsChanged(settings) = event {
+            *self.sensor.lock() = Some(settings);
+        }
+
+        let mut subscribers = self.subscribers.lock();
+        for sender in subscribers.iter_mut() {
+            // A subscriber whose channel is full misses the event
+            let _ = sender.try_send(event.clone());
+        }
+        subscribers.retain(|sender| !sender.is_closed());
+    }
+}
+
+#[test]
+fn test_control_state_publish() {
+    use futures::{FutureExt, StreamExt};
+
+    let state = ControlState::default();
+    let mut receiver = state.subscribe();
+    let dropped = state.subscribe();
+    drop(dropped);
+
+    let settings = SensorSettings {
+        exposure: Duration::from_millis(10),
+        analog_gain: 2.0,
+        digital_gain: 1.0,
+        awb_red_gain: 1.5,
+        awb_blue_gain: 1.25,
+    };
+    state.publish(CameraEvent::SettingsChanged(settings));
+    state.publish(CameraEvent::Unknown(*b"abcd"));
+
+    assert_eq!(*state.sensor.lock(), Some(settings));
+    assert_eq!(state.subscribers.lock().len(), 1);
+    assert_eq!(
+        receiver.next().now_or_never().unwrap(),
+        Some(CameraEvent::SettingsChanged(settings))
+    );
+    assert_eq!(
+        receiver.next().now_or_never().unwrap(),
+        Some(CameraEvent::Unknown(*b"abcd"))
+    );
+    assert_eq!(receiver.next().now_or_never(), None);
+
+    for _ in 0..EVENT_CHANNEL_SIZE * 2 {
+        state.publish(CameraEvent::Error);
+    }
+    assert_eq!(state.subscribers.lock().len(), 1);
+}
+
+#[test]
+fn test_settle_tracker() {
+    let settings = SensorSettings {
+        exposure: Duration::from_millis(20),
+        analog_gain: 4.0,
+        digital_gain: 1.0,
+        awb_red_gain: 1.5,
+        awb_blue_gain: 1.25,
+    };
+    let mut tracker = SettleTracker::default();
+
+    // Exposure ramping up
+    for i in 1..=SETTLE_REPORTS as u64 {
+        let ramping = SensorSettings {
+            exposure: Duration::from_millis(i * 3),
+            ..settings
+        };
+        assert!(!tracker.push(ramping));
+    }
+
+    // Small wobbles are fine but the reports have to agree for long enough
+    for i in 1..SETTLE_REPORTS {
+        let wobble = SensorSettings {
+            analog_gain: if i % 2 == 0 { 4.1 } else { 3.9 },
+            ..settings
+        };
+        assert!(!tracker.push(wobble));
+    }
+    assert!(tracker.push(settings));
+
+    // White balance moving again
+    let moved = SensorSettings {
+        awb_blue_gain: 2.0,
+        ..settings
+    };
+    assert!(!tracker.push(moved));
+}
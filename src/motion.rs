@@ -0,0 +1,508 @@
+//! Motion detection by comparing frames against a slowly adapting background.
+//!
+//! This works on the luma of small frames, e.g. 320x240 frames from a
+//! [`SplitterOutput::Resizer`](crate::SplitterOutput::Resizer), and doesn't need the camera.
+
+use std::cmp::Reverse;
+
+use crate::frame::{Frame, PixelFormat};
+
+/// Settings for a [`MotionDetector`].
+///
+/// ```
+/// # use rascam::motion::MotionSettings;
+/// let settings = MotionSettings {
+///     threshold: 40,
+///     min_area: 100,
+///     ..MotionSettings::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct MotionSettings {
+    /// How much the luma of a pixel must differ from the background for it to count as changed.
+    pub threshold: u8,
+    /// The number of changed pixels a region needs to be reported. Smaller regions are treated
+    /// as noise.
+    pub min_area: usize,
+    /// How quickly the background follows the frames, between 0 (never) and 1 (immediately).
+    ///
+    /// Lower values detect slower motion, but take longer to stop reporting things which
+    /// moved and then stayed put.
+    pub learning_rate: f32,
+    /// Pixels to ignore, row by row. `true` excludes the pixel from detection.
+    ///
+    /// Must be `width * height` long if given.
+    pub mask: Option<Vec<bool>>,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        MotionSettings {
+            threshold: 25,
+            min_area: 50,
+            learning_rate: 0.05,
+            mask: None,
+        }
+    }
+}
+
+/// A bounding box around a connected region of changed pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Number of changed pixels in the region
+    pub area: usize,
+}
+
+/// Motion found in a frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MotionEvent {
+    /// The regions which are at least [`MotionSettings::min_area`], largest first.
+    pub regions: Vec<Region>,
+    /// Total number of changed pixels, including those in regions too small to report.
+    pub changed: usize,
+}
+
+impl MotionEvent {
+    /// The bounding box around all of the regions.
+    pub fn bounds(&self) -> Region {
+        let x = self.regions.iter().map(|r| r.x).min().unwrap_or(0);
+        let y = self.regions.iter().map(|r| r.y).min().unwrap_or(0);
+        let right = self
+            .regions
+            .iter()
+            .map(|r| r.x + r.width)
+            .max()
+            .unwrap_or(0);
+        let bottom = self
+            .regions
+            .iter()
+            .map(|r| r.y + r.height)
+            .max()
+            .unwrap_or(0);
+
+        Region {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+            area: self.regions.iter().map(|r| r.area).sum(),
+        }
+    }
+}
+
+/// Detects motion by comparing each frame with a background model.
+///
+/// The background starts as the first frame and then slowly adapts to each new frame, so
+/// gradual changes such as the light level aren't reported.
+///
+/// ```no_run
+/// use rascam::motion::{MotionDetector, MotionSettings};
+/// use rascam::{PixelFormat, SimpleCamera, StreamSettings};
+///
+/// let info = rascam::info().unwrap();
+/// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+/// camera.activate().unwrap();
+///
+/// let settings = StreamSettings {
+///     format: PixelFormat::I420,
+///     width: 320,
+///     height: 240,
+///     ..StreamSettings::default()
+/// };
+/// let mut detector = MotionDetector::new(320, 240, MotionSettings::default());
+/// for frame in camera.stream(settings).unwrap() {
+///     if let Some(event) = detector.process(&frame.unwrap()) {
+///         println!("Motion at {:?}", event.bounds());
+///     }
+/// }
+/// ```
+pub struct MotionDetector {
+    settings: MotionSettings,
+    width: u32,
+    height: u32,
+    background: Vec<f32>,
+    changed: Vec<bool>,
+}
+
+impl MotionDetector {
+    /// Creates a detector for frames of the given size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the mask in the settings isn't `width * height` long.
+    pub fn new(width: u32, height: u32, settings: MotionSettings) -> MotionDetector {
+        let size = (width * height) as usize;
+        if let Some(mask) = settings.mask.as_ref() {
+            assert_eq!(mask.len(), size, "Mask is the wrong size");
+        }
+
+        MotionDetector {
+            settings,
+            width,
+            height,
+            background: Vec::new(),
+            changed: vec![false; size],
+        }
+    }
+
+    pub fn settings(&self) -> &MotionSettings {
+        &self.settings
+    }
+
+    /// Forgets the background so that the next frame becomes the new background.
+    pub fn reset(&mut self) {
+        self.background.clear();
+    }
+
+    /// Compares a frame with the background, returning any motion.
+    ///
+    /// The luma is used from YUV frames and calculated from RGB frames.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame isn't the size given to [`new`](MotionDetector::new).
+    pub fn process(&mut self, frame: &Frame) -> Option<MotionEvent> {
+        assert_eq!(
+            (frame.width(), frame.height()),
+            (self.width, self.height),
+            "Frame is the wrong size"
+        );
+
+        match frame.format() {
+            PixelFormat::I420 | PixelFormat::Nv12 => {
+                let (luma, stride) = frame.plane(0);
+                self.process_luma(luma, stride)
+            }
+            format => {
+                let luma = luma(frame, format);
+                self.process_luma(&luma, self.width as usize)
+            }
+        }
+    }
+
+    /// Compares a luma plane, whose rows are `stride` bytes apart, with the background.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the plane is too small for the size given to [`new`](MotionDetector::new).
+    pub fn process_luma(&mut self, luma: &[u8], stride: usize) -> Option<MotionEvent> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        assert!(stride >= width, "Stride is less than the width");
+        assert!(
+            height == 0 || luma.len() >= stride * (height - 1) + width,
+            "Luma plane is too small"
+        );
+
+        let rows = luma.chunks(stride).take(height);
+        if self.background.is_empty() {
+            self.background = rows
+                .flat_map(|row| row[..width].iter().map(|&y| y as f32))
+                .collect();
+            return None;
+        }
+
+        let threshold = self.settings.threshold as f32;
+        let rate = self.settings.learning_rate;
+        for (y, row) in rows.enumerate() {
+            let start = y * width;
+            let background = &mut self.background[start..start + width];
+            let flags = &mut self.changed[start..start + width];
+
+            for ((&luma, background), flag) in row.iter().zip(background).zip(flags) {
+                let luma = luma as f32;
+                *flag = (luma - *background).abs() > threshold;
+                *background += (luma - *background) * rate;
+            }
+        }
+
+        if let Some(mask) = self.settings.mask.as_ref() {
+            for (flag, &masked) in self.changed.iter_mut().zip(mask) {
+                *flag &= !masked;
+            }
+        }
+        let changed = self.changed.iter().filter(|&&flag| flag).count();
+
+        if changed < self.settings.min_area {
+            return None;
+        }
+
+        let mut regions = self.regions();
+        if regions.is_empty() {
+            return None;
+        }
+        regions.sort_by_key(|region| Reverse(region.area));
+
+        Some(MotionEvent { regions, changed })
+    }
+
+    /// Finds the connected regions of changed pixels which are large enough to report.
+    ///
+    /// This clears the changed flags.
+    fn regions(&mut self) -> Vec<Region> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let mut regions = Vec::new();
+        let mut stack = Vec::new();
+
+        for start in 0..self.changed.len() {
+            if !self.changed[start] {
+                continue;
+            }
+
+            self.changed[start] = false;
+            stack.push(start);
+            let (mut left, mut top) = (width, height);
+            let (mut right, mut bottom) = (0, 0);
+            let mut area = 0;
+
+            while let Some(index) = stack.pop() {
+                let (x, y) = (index % width, index / width);
+                left = left.min(x);
+                right = right.max(x);
+                top = top.min(y);
+                bottom = bottom.max(y);
+                area += 1;
+
+                // Pixels touching diagonally are part of the same region
+                for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                    for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                        let neighbour = ny * width + nx;
+                        if self.changed[neighbour] {
+                            self.changed[neighbour] = false;
+                            stack.push(neighbour);
+                        }
+                    }
+                }
+            }
+
+            if area >= self.settings.min_area {
+                regions.push(Region {
+                    x: left as u32,
+                    y: top as u32,
+                    width: (right - left + 1) as u32,
+                    height: (bottom - top + 1) as u32,
+                    area,
+                });
+            }
+        }
+
+        regions
+    }
+}
+
+/// Calculates the luma of a packed frame, using BT.601 weights for RGB.
+fn luma(frame: &Frame, format: PixelFormat) -> Vec<u8> {
+    let (data, stride) = frame.plane(0);
+    let width = frame.width() as usize;
+
+    let rgb = |size: usize, r: usize, b: usize| {
+        data.chunks(stride)
+            .take(frame.height() as usize)
+            .flat_map(move |row| {
+                row[..width * size].chunks_exact(size).map(move |p| {
+                    ((77 * p[r] as u32 + 150 * p[1] as u32 + 29 * p[b] as u32) >> 8) as u8
+                })
+            })
+            .collect()
+    };
+
+    match format {
+        PixelFormat::Rgb24 => rgb(3, 0, 2),
+        PixelFormat::Bgr24 => rgb(3, 2, 0),
+        PixelFormat::Rgba => rgb(4, 0, 2),
+        PixelFormat::Yuyv => data
+            .chunks(stride)
+            .take(frame.height() as usize)
+            .flat_map(|row| row.iter().step_by(2).take(width).copied())
+            .collect(),
+        PixelFormat::I420 | PixelFormat::Nv12 => {
+            unreachable!("The luma plane is used directly")
+        }
+    }
+}
+
+/// A `width`x`height` luma frame of `background` with a `size` square of `value` at `x`, `y`.
+#[cfg(test)]
+fn square(width: usize, height: usize, x: usize, y: usize, size: usize, value: u8) -> Vec<u8> {
+    let background = 100;
+    let mut luma = vec![background; width * height];
+    for row in y..y + size {
+        for pixel in &mut luma[row * width + x..row * width + x + size] {
+            *pixel = value;
+        }
+    }
+    luma
+}
+
+#[test]
+fn test_motion_moving_square() {
+    let mut detector = MotionDetector::new(64, 48, MotionSettings::default());
+    assert_eq!(detector.process_luma(&square(64, 48, 0, 0, 0, 0), 64), None);
+    assert_eq!(detector.process_luma(&square(64, 48, 0, 0, 0, 0), 64), None);
+
+    let event = detector
+        .process_luma(&square(64, 48, 10, 20, 8, 200), 64)
+        .unwrap();
+    assert_eq!(event.changed, 64);
+    assert_eq!(
+        event.regions,
+        vec![Region {
+            x: 10,
+            y: 20,
+            width: 8,
+            height: 8,
+            area: 64
+        }]
+    );
+}
+
+#[test]
+fn test_motion_separate_regions() {
+    let mut detector = MotionDetector::new(64, 48, MotionSettings::default());
+    detector.process_luma(&square(64, 48, 0, 0, 0, 0), 64);
+
+    let mut luma = square(64, 48, 2, 2, 10, 0);
+    for row in 30..36 {
+        for pixel in &mut luma[row * 64 + 50..row * 64 + 60] {
+            *pixel = 255;
+        }
+    }
+
+    let event = detector.process_luma(&luma, 64).unwrap();
+    assert_eq!(event.changed, 160);
+    assert_eq!(event.regions.len(), 2);
+    assert_eq!(event.regions[0].area, 100);
+    assert_eq!(event.regions[1].area, 60);
+    assert_eq!(
+        event.bounds(),
+        Region {
+            x: 2,
+            y: 2,
+            width: 58,
+            height: 34,
+            area: 160
+        }
+    );
+}
+
+#[test]
+fn test_motion_ignores_noise() {
+    let settings = MotionSettings {
+        min_area: 20,
+        ..MotionSettings::default()
+    };
+    let mut detector = MotionDetector::new(64, 48, settings);
+    detector.process_luma(&square(64, 48, 0, 0, 0, 0), 64);
+
+    // Changes below the threshold
+    assert_eq!(detector.process_luma(&vec![120; 64 * 48], 64), None);
+
+    // Scattered pixels that are never connected
+    let mut luma = vec![100; 64 * 48];
+    for i in (0..luma.len()).step_by(7 * 64 + 3) {
+        luma[i] = 255;
+    }
+    assert_eq!(detector.process_luma(&luma, 64), None);
+
+    // A region smaller than the minimum area
+    assert_eq!(
+        detector.process_luma(&square(64, 48, 5, 5, 4, 255), 64),
+        None
+    );
+}
+
+#[test]
+fn test_motion_mask() {
+    let mut mask = vec![false; 64 * 48];
+    for row in 0..24 {
+        for pixel in &mut mask[row * 64..row * 64 + 32] {
+            *pixel = true;
+        }
+    }
+    let settings = MotionSettings {
+        mask: Some(mask),
+        ..MotionSettings::default()
+    };
+    let mut detector = MotionDetector::new(64, 48, settings);
+    detector.process_luma(&square(64, 48, 0, 0, 0, 0), 64);
+
+    assert_eq!(
+        detector.process_luma(&square(64, 48, 4, 4, 10, 255), 64),
+        None
+    );
+
+    // Partly masked, so only the visible part is reported
+    let event = detector
+        .process_luma(&square(64, 48, 28, 10, 10, 0), 64)
+        .unwrap();
+    assert_eq!(
+        event.regions,
+        vec![Region {
+            x: 32,
+            y: 10,
+            width: 6,
+            height: 10,
+            area: 60
+        }]
+    );
+}
+
+#[test]
+fn test_motion_background_adapts() {
+    let mut detector = MotionDetector::new(64, 48, MotionSettings::default());
+    detector.process_luma(&square(64, 48, 0, 0, 0, 0), 64);
+
+    // Something arrives and stays still
+    let luma = square(64, 48, 20, 20, 10, 200);
+    assert!(detector.process_luma(&luma, 64).is_some());
+
+    let mut frames = 1;
+    while detector.process_luma(&luma, 64).is_some() {
+        frames += 1;
+        assert!(frames < 100, "Background never adapted");
+    }
+    // 100 * 0.95^n must fall below 25
+    assert!(frames > 20);
+
+    // A gradual change in brightness is never reported
+    detector.reset();
+    for level in 100..200 {
+        assert_eq!(detector.process_luma(&vec![level; 64 * 48], 64), None);
+    }
+}
+
+#[test]
+fn test_motion_frame_formats() {
+    let settings = MotionSettings {
+        min_area: 1,
+        ..MotionSettings::default()
+    };
+
+    // A padded I420 frame from the camera
+    let mut detector = MotionDetector::new(40, 20, settings.clone());
+    let size = Frame::camera_size(PixelFormat::I420, 40, 20);
+    let frame = Frame::new(vec![100; size], PixelFormat::I420, 40, 20);
+    assert_eq!(detector.process(&frame), None);
+
+    let mut data = vec![100; size];
+    data[64 * 3 + 5] = 255;
+    let frame = Frame::new(data, PixelFormat::I420, 40, 20);
+    let event = detector.process(&frame).unwrap();
+    assert_eq!((event.regions[0].x, event.regions[0].y), (5, 3));
+
+    // RGB uses the calculated luma
+    let mut detector = MotionDetector::new(4, 2, settings);
+    let frame = Frame::packed(vec![0; 4 * 2 * 3], PixelFormat::Rgb24, 4, 2);
+    assert_eq!(detector.process(&frame), None);
+
+    let mut data = vec![0; 4 * 2 * 3];
+    data[(4 + 2) * 3 + 1] = 255;
+    let frame = Frame::packed(data, PixelFormat::Rgb24, 4, 2);
+    let event = detector.process(&frame).unwrap();
+    assert_eq!((event.regions[0].x, event.regions[0].y), (2, 1));
+}
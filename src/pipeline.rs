@@ -0,0 +1,245 @@
+use ffi::MMAL_STATUS_T;
+use mmal_sys as ffi;
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::os::raw::c_char;
+use std::ptr::NonNull;
+use tracing::debug;
+
+use crate::error::{CameraError, MmalError, Stage};
+
+/// An MMAL component other than the camera, such as an encoder or splitter.
+///
+/// The component is disabled and destroyed when dropped.
+pub(crate) struct Component {
+    component: NonNull<ffi::MMAL_COMPONENT_T>,
+    enabled: bool,
+    stage: Stage,
+}
+
+impl Component {
+    /// Creates a component from its nul terminated name, e.g. `MMAL_COMPONENT_DEFAULT_VIDEO_ENCODER`.
+    ///
+    /// `stage` is the part of the pipeline that the component is, for reporting errors.
+    pub(crate) fn new(name: &[u8], stage: Stage) -> Result<Component, CameraError> {
+        unsafe {
+            let mut component_ptr = MaybeUninit::uninit();
+            let status = ffi::mmal_component_create(
+                name.as_ptr() as *const c_char,
+                component_ptr.as_mut_ptr(),
+            );
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => Ok(Component {
+                    component: NonNull::new(component_ptr.assume_init()).unwrap(),
+                    enabled: false,
+                    stage,
+                }),
+                s => Err(MmalError::with_status(
+                    format!(
+                        "Unable to create component {}",
+                        CStr::from_bytes_with_nul(name).unwrap().to_string_lossy()
+                    ),
+                    s,
+                )
+                .with_stage(stage)
+                .into()),
+            }
+        }
+    }
+
+    pub(crate) fn input(&self, index: isize) -> *mut ffi::MMAL_PORT_T {
+        unsafe { *self.component.as_ref().input.offset(index) }
+    }
+
+    pub(crate) fn output(&self, index: isize) -> *mut ffi::MMAL_PORT_T {
+        unsafe { *self.component.as_ref().output.offset(index) }
+    }
+
+    pub(crate) fn output_num(&self) -> usize {
+        unsafe { self.component.as_ref().output_num as usize }
+    }
+
+    pub(crate) fn enable(&mut self) -> Result<(), CameraError> {
+        unsafe {
+            let status = ffi::mmal_component_enable(self.component.as_ptr());
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => {
+                    self.enabled = true;
+                    Ok(())
+                }
+                s => Err(MmalError::with_status(
+                    format!(
+                        "Unable to enable component {}",
+                        CStr::from_ptr(self.component.as_ref().name).to_string_lossy()
+                    ),
+                    s,
+                )
+                .with_stage(self.stage)
+                .into()),
+            }
+        }
+    }
+}
+
+impl Drop for Component {
+    fn drop(&mut self) {
+        unsafe {
+            if self.enabled {
+                ffi::mmal_component_disable(self.component.as_ptr());
+            }
+            ffi::mmal_component_destroy(self.component.as_ptr());
+        }
+    }
+}
+
+/// A tunnelled connection between an output port and an input port.
+///
+/// Creating the connection copies the output port's format to the input port. The connection
+/// is disabled and destroyed when dropped.
+pub(crate) struct Connection {
+    connection: NonNull<ffi::MMAL_CONNECTION_T>,
+    enabled: bool,
+}
+
+impl Connection {
+    /// # Safety
+    ///
+    /// Both ports must be valid and outlive the connection.
+    pub(crate) unsafe fn new(
+        output: *mut ffi::MMAL_PORT_T,
+        input: *mut ffi::MMAL_PORT_T,
+    ) -> Result<Connection, CameraError> {
+        let mut connection_ptr = MaybeUninit::uninit();
+        let status = ffi::mmal_connection_create(
+            connection_ptr.as_mut_ptr(),
+            output,
+            input,
+            ffi::MMAL_CONNECTION_FLAG_TUNNELLING | ffi::MMAL_CONNECTION_FLAG_ALLOCATION_ON_INPUT,
+        );
+        match status {
+            MMAL_STATUS_T::MMAL_SUCCESS => Ok(Connection {
+                connection: NonNull::new(connection_ptr.assume_init()).unwrap(),
+                enabled: false,
+            }),
+            s => Err(MmalError::with_status(
+                format!(
+                    "Unable to connect {} to {}",
+                    CStr::from_ptr((*output).name).to_string_lossy(),
+                    CStr::from_ptr((*input).name).to_string_lossy()
+                ),
+                s,
+            )
+            .with_stage(Stage::Connection)
+            .into()),
+        }
+    }
+
+    pub(crate) fn enable(&mut self) -> Result<(), CameraError> {
+        unsafe {
+            let status = ffi::mmal_connection_enable(self.connection.as_ptr());
+            match status {
+                MMAL_STATUS_T::MMAL_SUCCESS => {
+                    self.enabled = true;
+                    Ok(())
+                }
+                s => Err(
+                    MmalError::with_status("Unable to enable connection".to_owned(), s)
+                        .with_stage(Stage::Connection)
+                        .into(),
+                ),
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            if self.enabled {
+                ffi::mmal_connection_disable(self.connection.as_ptr());
+            }
+            ffi::mmal_connection_destroy(self.connection.as_ptr());
+        }
+    }
+}
+
+/// Components and connections which sit between the camera and one or more streams.
+///
+/// Streams share the pipeline they read from so that it stays alive until the last of them
+/// is dropped. Capture is then stopped and everything is torn down, connections first.
+#[derive(Default)]
+pub(crate) struct Pipeline {
+    connections: Vec<Connection>,
+    components: Vec<Component>,
+    capture_port: Option<NonNull<ffi::MMAL_PORT_T>>,
+}
+
+// SAFETY: The pipeline owns its components and connections, and MMAL doesn't tie either to
+// the thread that created them, so they can be torn down wherever the pipeline is dropped. It
+// has no `&self` methods: streams share it through an `Arc` only to keep it alive, so a shared
+// pipeline is never used until the last owner drops it.
+unsafe impl Send for Pipeline {}
+unsafe impl Sync for Pipeline {}
+
+impl Pipeline {
+    pub(crate) fn add_component(&mut self, component: Component) {
+        self.components.push(component);
+    }
+
+    pub(crate) fn add_connection(&mut self, connection: Connection) {
+        self.connections.push(connection);
+    }
+
+    pub(crate) fn enable(&mut self) -> Result<(), CameraError> {
+        for component in self.components.iter_mut() {
+            component.enable()?;
+        }
+        for connection in self.connections.iter_mut() {
+            connection.enable()?;
+        }
+        Ok(())
+    }
+
+    /// Starts capturing on a camera output port. Capture is stopped when the pipeline is
+    /// dropped.
+    ///
+    /// # Safety
+    ///
+    /// `port` must be a valid camera output port which outlives the pipeline.
+    pub(crate) unsafe fn start_capture(
+        &mut self,
+        port: *mut ffi::MMAL_PORT_T,
+    ) -> Result<(), CameraError> {
+        let status = ffi::mmal_port_parameter_set_boolean(port, ffi::MMAL_PARAMETER_CAPTURE, 1);
+        match status {
+            MMAL_STATUS_T::MMAL_SUCCESS => {
+                self.capture_port = NonNull::new(port);
+                Ok(())
+            }
+            s => Err(
+                MmalError::with_status("Unable to start video capture".to_owned(), s)
+                    .with_stage(Stage::Camera)
+                    .into(),
+            ),
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(port) = self.capture_port {
+                ffi::mmal_port_parameter_set_boolean(port.as_ptr(), ffi::MMAL_PARAMETER_CAPTURE, 0);
+                debug!("video capture stopped");
+            }
+        }
+
+        while let Some(connection) = self.connections.pop() {
+            drop(connection);
+        }
+        while let Some(component) = self.components.pop() {
+            drop(component);
+        }
+        debug!("pipeline destroyed");
+    }
+}
@@ -0,0 +1,272 @@
+use ffi::MMAL_STATUS_T;
+use mmal_sys as ffi;
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::ptr::NonNull;
+use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::error::{CameraError, ErrorKind, MmalError, Stage};
+use crate::queue::{BufferQueue, Overflowed};
+use crate::settings::Backpressure;
+
+/// Receives the buffers produced by an output port.
+///
+/// Creating a sink enables the port and hands it all of the buffers in a new pool. Each buffer
+/// the port fills is added to the sink's queue and is given back to the port when the
+/// [`PortBuffer`] is dropped, so the number of buffers limits how many frames can be in
+/// flight at once. What happens when the consumer falls behind is set by [`Backpressure`].
+///
+/// Dropping the sink disables the port and destroys the pool.
+pub(crate) struct PortSink {
+    port: NonNull<ffi::MMAL_PORT_T>,
+    pool: NonNull<ffi::MMAL_POOL_T>,
+    queue: Arc<BufferQueue<PortBuffer>>,
+    stage: Stage,
+}
+
+struct SinkUserdata {
+    pool: NonNull<ffi::MMAL_POOL_T>,
+    queue: Arc<BufferQueue<PortBuffer>>,
+}
+
+impl PortSink {
+    /// Creates a pool of `buffer_num` buffers, enables the port and sends it the buffers.
+    ///
+    /// `stage` is the part of the pipeline that the port belongs to, for reporting errors.
+    ///
+    /// # Safety
+    ///
+    /// `port` must be a valid, disabled output port whose format has been committed and which
+    /// outlives the sink.
+    pub(crate) unsafe fn new(
+        port: *mut ffi::MMAL_PORT_T,
+        buffer_num: u32,
+        stage: Stage,
+    ) -> Result<PortSink, CameraError> {
+        (*port).buffer_num = buffer_num.max((*port).buffer_num_min);
+        (*port).buffer_size = (*port).buffer_size_recommended.max((*port).buffer_size_min);
+
+        let pool = ffi::mmal_port_pool_create(port, (*port).buffer_num, (*port).buffer_size);
+        let pool = match NonNull::new(pool) {
+            Some(pool) => pool,
+            None => {
+                debug!(
+                    "Failed to create buffer header pool for port {}",
+                    CStr::from_ptr((*port).name).to_string_lossy()
+                );
+                return Err(ErrorKind::PoolExhausted(Stage::Pool).into());
+            }
+        };
+
+        // Dropping buffers only starts once all but one are waiting, so the port always has
+        // a buffer to fill.
+        let max_queued = (*port).buffer_num.saturating_sub(1) as usize;
+        let queue = Arc::new(BufferQueue::new(max_queued));
+        let userdata = SinkUserdata {
+            pool,
+            queue: Arc::clone(&queue),
+        };
+        (*port).userdata = Box::into_raw(Box::new(userdata)) as *mut ffi::MMAL_PORT_USERDATA_T;
+
+        let sink = PortSink {
+            port: NonNull::new(port).unwrap(),
+            pool,
+            queue,
+            stage,
+        };
+
+        let status = ffi::mmal_port_enable(port, Some(sink_callback));
+        if status != MMAL_STATUS_T::MMAL_SUCCESS {
+            return Err(
+                MmalError::with_status("Unable to enable port".to_owned(), status)
+                    .with_stage(stage)
+                    .into(),
+            );
+        }
+
+        let num = ffi::mmal_queue_length(pool.as_ref().queue);
+        for i in 0..num {
+            let buffer = ffi::mmal_queue_get(pool.as_ref().queue);
+            if buffer.is_null() {
+                debug!("Unable to get a required buffer {} from pool queue", i);
+                return Err(ErrorKind::PoolExhausted(Stage::Pool).into());
+            }
+
+            let status = ffi::mmal_port_send_buffer(port, buffer);
+            if status != MMAL_STATUS_T::MMAL_SUCCESS {
+                return Err(MmalError::with_status(
+                    format!("Unable to send a buffer to port ({})", i),
+                    status,
+                )
+                .with_stage(stage)
+                .into());
+            }
+        }
+
+        Ok(sink)
+    }
+
+    /// Waits for the next buffer from the port, giving up after `timeout` if there is one.
+    ///
+    /// Returns [`ErrorKind::Overflow`] once if buffers were dropped with
+    /// [`Backpressure::Error`].
+    pub(crate) fn recv(&self, timeout: Option<Duration>) -> Result<PortBuffer, CameraError> {
+        match self.queue.pop(timeout) {
+            Ok(Some(buffer)) => Ok(PortBuffer::popped(buffer)),
+            Ok(None) => Err(ErrorKind::Timeout(self.stage).into()),
+            Err(Overflowed) => Err(ErrorKind::Overflow(self.stage).into()),
+        }
+    }
+
+    /// Returns the next buffer from the port if one is waiting.
+    pub(crate) fn try_recv(&self) -> Result<Option<PortBuffer>, CameraError> {
+        match self.queue.try_pop() {
+            Ok(buffer) => Ok(buffer.map(PortBuffer::popped)),
+            Err(Overflowed) => Err(ErrorKind::Overflow(self.stage).into()),
+        }
+    }
+
+    pub(crate) fn set_backpressure(&self, backpressure: Backpressure) {
+        self.queue.set_backpressure(backpressure);
+    }
+
+    /// Number of buffers dropped because they weren't received in time.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+}
+
+// SAFETY: The callback and the consumer only share the queue, which has its own lock. The port
+// and pool pointers are otherwise only used in `drop`, which disables the port before freeing
+// the callback state, so that is safe on whichever thread the sink ends up on.
+unsafe impl Send for PortSink {}
+
+impl Drop for PortSink {
+    fn drop(&mut self) {
+        unsafe {
+            let port = self.port.as_ptr();
+
+            if (*port).is_enabled > 0 {
+                ffi::mmal_port_disable(port);
+            }
+
+            // No more callbacks can happen once the port is disabled
+            if !(*port).userdata.is_null() {
+                drop(Box::from_raw((*port).userdata as *mut SinkUserdata));
+                (*port).userdata = ptr::null_mut();
+            }
+
+            // Return any buffers that were never received to the pool
+            self.queue.clear();
+
+            ffi::mmal_port_pool_destroy(port, self.pool.as_ptr());
+            debug!("port sink destroyed");
+        }
+    }
+}
+
+/// A buffer filled by a port. Returned to the port when dropped.
+pub(crate) struct PortBuffer {
+    port: *mut ffi::MMAL_PORT_T,
+    pool: NonNull<ffi::MMAL_POOL_T>,
+    buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
+    after_gap: bool,
+}
+
+impl PortBuffer {
+    fn popped((mut buffer, after_gap): (PortBuffer, bool)) -> PortBuffer {
+        buffer.after_gap = after_gap;
+        buffer
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        unsafe {
+            let buffer = *self.buffer;
+            if buffer.length == 0 {
+                return &[];
+            }
+            slice::from_raw_parts(
+                buffer.data.offset(buffer.offset as isize),
+                buffer.length as usize,
+            )
+        }
+    }
+
+    pub(crate) fn flags(&self) -> u32 {
+        unsafe { (*self.buffer).flags }
+    }
+
+    pub(crate) fn pts(&self) -> i64 {
+        unsafe { (*self.buffer).pts }
+    }
+
+    /// Whether buffers were dropped by the [`Backpressure`] policy between this buffer and the
+    /// one received before it.
+    pub(crate) fn after_gap(&self) -> bool {
+        self.after_gap
+    }
+}
+
+// SAFETY: From the callback until it is dropped, the port buffer is the only owner of its
+// header, which stays locked so the data can't move. Handing it back in `drop` goes through
+// the pool's queue and `mmal_port_send_buffer`, both of which take MMAL's own locks.
+unsafe impl Send for PortBuffer {}
+
+impl Drop for PortBuffer {
+    /// Releases the buffer back to the pool and sends a new buffer to the port (if still open).
+    fn drop(&mut self) {
+        unsafe {
+            ffi::mmal_buffer_header_mem_unlock(self.buffer);
+            ffi::mmal_buffer_header_release(self.buffer);
+
+            if (*self.port).is_enabled > 0 {
+                let mut status = MMAL_STATUS_T::MMAL_STATUS_MAX;
+                let new_buffer = ffi::mmal_queue_get(self.pool.as_ref().queue);
+
+                if !new_buffer.is_null() {
+                    status = ffi::mmal_port_send_buffer(self.port, new_buffer);
+                }
+
+                if new_buffer.is_null() || status != MMAL_STATUS_T::MMAL_SUCCESS {
+                    debug!("Unable to return the buffer to the port");
+                }
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn sink_callback(
+    port: *mut ffi::MMAL_PORT_T,
+    buffer: *mut ffi::MMAL_BUFFER_HEADER_T,
+) {
+    // Unwinding into C is undefined behaviour
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handle_sink_buffer(port, buffer)));
+    if result.is_err() {
+        debug!("Panicked while handling a port buffer");
+    }
+}
+
+unsafe fn handle_sink_buffer(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    let userdata = (*port).userdata as *mut SinkUserdata;
+
+    if userdata.is_null() {
+        debug!("Received a port buffer callback with no state");
+        ffi::mmal_buffer_header_release(buffer);
+        return;
+    }
+
+    ffi::mmal_buffer_header_mem_lock(buffer);
+    let buffer = PortBuffer {
+        port,
+        pool: (*userdata).pool,
+        buffer,
+        after_gap: false,
+    };
+
+    // Never waits for the consumer. Any buffer the queue drops goes straight back to the port.
+    (*userdata).queue.push(buffer);
+}
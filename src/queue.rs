@@ -0,0 +1,248 @@
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::settings::Backpressure;
+
+/// Returned by [`BufferQueue::pop`] when buffers were dropped because of
+/// [`Backpressure::Error`]. Only reported once for each time the queue overflows.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Overflowed;
+
+/// Buffers waiting to be received, shared between a port's callback and its consumer.
+///
+/// Pushing never waits for the consumer, so the callback thread is never blocked. Instead,
+/// once `max_queued` buffers are waiting the [`Backpressure`] policy decides which buffer to
+/// drop. [`Backpressure::Block`] never drops anything and relies on the port running out of
+/// buffers instead.
+///
+/// Each buffer is popped along with whether buffers were dropped just before it, so a consumer
+/// that joins several buffers together knows when it has lost part of the data.
+pub(crate) struct BufferQueue<T> {
+    state: Mutex<QueueState<T>>,
+    ready: Condvar,
+}
+
+struct QueueState<T> {
+    /// Each buffer with whether buffers were dropped just before it.
+    buffers: VecDeque<(T, bool)>,
+    backpressure: Backpressure,
+    max_queued: usize,
+    dropped: u64,
+    overflowed: bool,
+    /// Whether a buffer was dropped since the last one was queued.
+    gap: bool,
+}
+
+impl<T> BufferQueue<T> {
+    pub(crate) fn new(max_queued: usize) -> BufferQueue<T> {
+        BufferQueue {
+            state: Mutex::new(QueueState {
+                buffers: VecDeque::new(),
+                backpressure: Backpressure::Block,
+                max_queued: max_queued.max(1),
+                dropped: 0,
+                overflowed: false,
+                gap: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn set_backpressure(&self, backpressure: Backpressure) {
+        self.state.lock().backpressure = backpressure;
+    }
+
+    /// Number of buffers that have been dropped because the consumer fell behind.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.state.lock().dropped
+    }
+
+    /// Adds a buffer to the back of the queue, or drops a buffer if too many are waiting.
+    pub(crate) fn push(&self, buffer: T) {
+        let dropped = {
+            let mut state = self.state.lock();
+            let full = state.buffers.len() >= state.max_queued;
+
+            let dropped = match state.backpressure {
+                Backpressure::DropNewest if full => {
+                    state.gap = true;
+                    Some(buffer)
+                }
+                Backpressure::DropOldest if full => {
+                    let gap = std::mem::replace(&mut state.gap, false);
+                    state.buffers.push_back((buffer, gap));
+                    let dropped = state.buffers.pop_front().map(|(buffer, _)| buffer);
+                    // The buffer now at the front followed the one that was dropped
+                    if let Some(front) = state.buffers.front_mut() {
+                        front.1 = true;
+                    }
+                    dropped
+                }
+                Backpressure::Error if full => {
+                    state.overflowed = true;
+                    state.gap = true;
+                    Some(buffer)
+                }
+                _ => {
+                    let gap = std::mem::replace(&mut state.gap, false);
+                    state.buffers.push_back((buffer, gap));
+                    None
+                }
+            };
+
+            if dropped.is_some() {
+                state.dropped += 1;
+            }
+            self.ready.notify_one();
+            dropped
+        };
+
+        // Dropping a buffer can hand it back to its port, which doesn't need the lock
+        drop(dropped);
+    }
+
+    /// Takes the buffer at the front of the queue, waiting up to `timeout` for one if there
+    /// is a timeout or forever if not. Returns `None` if the timeout expires.
+    ///
+    /// The buffer comes with whether buffers were dropped between it and the one before it.
+    pub(crate) fn pop(&self, timeout: Option<Duration>) -> Result<Option<(T, bool)>, Overflowed> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock();
+
+        loop {
+            if state.overflowed {
+                state.overflowed = false;
+                return Err(Overflowed);
+            }
+            if let Some(buffer) = state.buffers.pop_front() {
+                return Ok(Some(buffer));
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if self.ready.wait_until(&mut state, deadline).timed_out() {
+                        return Ok(state.buffers.pop_front());
+                    }
+                }
+                None => self.ready.wait(&mut state),
+            }
+        }
+    }
+
+    /// Takes the buffer at the front of the queue if there is one.
+    pub(crate) fn try_pop(&self) -> Result<Option<(T, bool)>, Overflowed> {
+        self.pop(Some(Duration::from_secs(0)))
+    }
+
+    /// Drops every waiting buffer.
+    pub(crate) fn clear(&self) {
+        let buffers = std::mem::replace(&mut self.state.lock().buffers, VecDeque::new());
+        drop(buffers);
+    }
+}
+
+#[test]
+fn test_buffer_queue_block() {
+    let queue = BufferQueue::new(2);
+    for i in 0..4 {
+        queue.push(i);
+    }
+    assert_eq!(queue.dropped(), 0);
+
+    for i in 0..4 {
+        assert_eq!(queue.try_pop(), Ok(Some((i, false))));
+    }
+    assert_eq!(queue.try_pop(), Ok(None));
+}
+
+#[test]
+fn test_buffer_queue_drop_newest() {
+    let queue = BufferQueue::new(2);
+    queue.set_backpressure(Backpressure::DropNewest);
+    for i in 0..4 {
+        queue.push(i);
+    }
+    assert_eq!(queue.dropped(), 2);
+
+    assert_eq!(queue.try_pop(), Ok(Some((0, false))));
+    assert_eq!(queue.try_pop(), Ok(Some((1, false))));
+    assert_eq!(queue.try_pop(), Ok(None));
+
+    // The next buffer to be queued follows the ones that were dropped
+    queue.push(4);
+    assert_eq!(queue.try_pop(), Ok(Some((4, true))));
+}
+
+#[test]
+fn test_buffer_queue_drop_oldest() {
+    let queue = BufferQueue::new(2);
+    queue.set_backpressure(Backpressure::DropOldest);
+    for i in 0..5 {
+        queue.push(i);
+    }
+    assert_eq!(queue.dropped(), 3);
+
+    assert_eq!(queue.try_pop(), Ok(Some((3, true))));
+    assert_eq!(queue.try_pop(), Ok(Some((4, false))));
+    assert_eq!(queue.try_pop(), Ok(None));
+}
+
+#[test]
+fn test_buffer_queue_error() {
+    let queue = BufferQueue::new(1);
+    queue.set_backpressure(Backpressure::Error);
+    queue.push(0);
+    queue.push(1);
+    queue.push(2);
+    assert_eq!(queue.dropped(), 2);
+
+    // The overflow is reported once, then the buffers that were kept are received
+    assert_eq!(queue.try_pop(), Err(Overflowed));
+    assert_eq!(queue.try_pop(), Ok(Some((0, false))));
+    assert_eq!(queue.try_pop(), Ok(None));
+
+    queue.push(3);
+    assert_eq!(queue.try_pop(), Ok(Some((3, true))));
+}
+
+#[test]
+fn test_buffer_queue_multi_buffer_frame() {
+    // Two frames of three buffers each, with the consumer falling behind partway through the
+    // first
+    let queue = BufferQueue::new(2);
+    queue.set_backpressure(Backpressure::DropOldest);
+    queue.push("a0");
+    queue.push("a1");
+    assert_eq!(queue.try_pop(), Ok(Some(("a0", false))));
+    queue.push("a2");
+    queue.push("b0");
+    assert_eq!(queue.dropped(), 1);
+
+    // The middle of the first frame is lost, so the end of it is marked as coming after a gap
+    // and the consumer knows to throw away the start it already has
+    assert_eq!(queue.try_pop(), Ok(Some(("a2", true))));
+    assert_eq!(queue.try_pop(), Ok(Some(("b0", false))));
+    queue.push("b1");
+    queue.push("b2");
+    assert_eq!(queue.try_pop(), Ok(Some(("b1", false))));
+    assert_eq!(queue.try_pop(), Ok(Some(("b2", false))));
+    assert_eq!(queue.try_pop(), Ok(None));
+}
+
+#[test]
+fn test_buffer_queue_timeout() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let queue = Arc::new(BufferQueue::new(1));
+    assert_eq!(queue.pop(Some(Duration::from_millis(10))), Ok(None));
+
+    let producer = Arc::clone(&queue);
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        producer.push(7);
+    });
+    assert_eq!(queue.pop(None), Ok(Some((7, false))));
+    handle.join().unwrap();
+}
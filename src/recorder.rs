@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::mem;
+use std::time::Duration;
+
+use crate::error::CameraError;
+use crate::stream::EncodedBuffer;
+
+/// How much video a [`CircularRecorder`] keeps before it is triggered.
+#[derive(Clone, Copy, Debug)]
+pub enum RecorderLimit {
+    /// Keep at least this much video, measured using the buffer timestamps.
+    ///
+    /// Nothing is discarded while the encoder doesn't provide timestamps.
+    Duration(Duration),
+    /// Keep at most this many bytes of video.
+    ///
+    /// The most recent group of pictures is always kept, even if it is larger than the limit.
+    Bytes(usize),
+}
+
+/// A keyframe, along with the headers needed to decode it, and the frames which follow it.
+struct GroupOfPictures {
+    data: Vec<u8>,
+    start: Option<i64>,
+}
+
+/// Keeps the last few seconds of encoded H.264 in memory so that video from before an event
+/// can be saved.
+///
+/// Buffers from a [`VideoStream`] are given to [`push`]. Until [`trigger`] is called they are
+/// only kept in memory, with the oldest discarded once the [`RecorderLimit`] is reached. The
+/// history always starts at a keyframe, preceded by the SPS and PPS headers, so it can be
+/// decoded on its own.
+///
+/// [`trigger`] writes the history to a sink and then every buffer pushed afterwards, until
+/// [`stop`] is called.
+///
+/// ```no_run
+/// use rascam::{CircularRecorder, EncoderSettings, RecorderLimit, SimpleCamera};
+/// use rascam::{SplitterOutput, VideoSettings};
+/// use std::fs::File;
+/// use std::time::Duration;
+///
+/// let info = rascam::info().unwrap();
+/// let mut camera = SimpleCamera::new(info.cameras[0].clone()).unwrap();
+/// camera.activate().unwrap();
+///
+/// let outputs = [SplitterOutput::Encoder(EncoderSettings::default())];
+/// let mut streams = camera.split(VideoSettings::default(), &outputs).unwrap();
+/// let video = streams.pop().unwrap().into_video().unwrap();
+///
+/// let mut recorder = CircularRecorder::new(RecorderLimit::Duration(Duration::from_secs(5)));
+/// for (i, buffer) in video.enumerate() {
+///     recorder.push(&buffer.unwrap()).unwrap();
+///
+///     if i == 300 {
+///         recorder.trigger(File::create("event.h264").unwrap()).unwrap();
+///     } else if i == 600 {
+///         recorder.stop().unwrap();
+///         break;
+///     }
+/// }
+/// ```
+///
+/// [`VideoStream`]: crate::VideoStream
+/// [`push`]: CircularRecorder::push
+/// [`trigger`]: CircularRecorder::trigger
+/// [`stop`]: CircularRecorder::stop
+pub struct CircularRecorder<W> {
+    limit: RecorderLimit,
+    /// The most recent SPS and PPS
+    headers: Vec<u8>,
+    /// Whether the previous buffer was part of the headers
+    in_headers: bool,
+    /// Whether the next buffer starts a new frame
+    frame_start: bool,
+    history: VecDeque<GroupOfPictures>,
+    size: usize,
+    latest_pts: Option<i64>,
+    sink: Option<W>,
+    /// Whether the sink has been given a keyframe to start from
+    synced: bool,
+}
+
+impl<W: Write> CircularRecorder<W> {
+    pub fn new(limit: RecorderLimit) -> CircularRecorder<W> {
+        CircularRecorder {
+            limit,
+            headers: Vec::new(),
+            in_headers: false,
+            frame_start: true,
+            history: VecDeque::new(),
+            size: 0,
+            latest_pts: None,
+            sink: None,
+            synced: false,
+        }
+    }
+
+    /// Adds a buffer from the encoder, writing it to the sink if the recorder has been
+    /// triggered.
+    ///
+    /// Buffers before the first keyframe are discarded because they can't be decoded, as are
+    /// side information buffers such as motion vectors.
+    pub fn push(&mut self, buffer: &EncodedBuffer) -> Result<(), CameraError> {
+        if buffer.is_side_info() {
+            return Ok(());
+        }
+
+        if buffer.pts().is_some() {
+            self.latest_pts = buffer.pts();
+        }
+
+        // The SPS and PPS may arrive as separate buffers. They are kept aside and written
+        // before the next keyframe.
+        if buffer.is_config() {
+            if !mem::replace(&mut self.in_headers, true) {
+                self.headers.clear();
+            }
+            self.headers.extend_from_slice(buffer.data());
+            return Ok(());
+        }
+        self.in_headers = false;
+
+        let frame_start = mem::replace(&mut self.frame_start, buffer.is_frame_end());
+        let new_group = frame_start && buffer.is_keyframe();
+
+        if new_group {
+            let mut data = Vec::with_capacity(self.headers.len() + buffer.data().len());
+            data.extend_from_slice(&self.headers);
+            self.history.push_back(GroupOfPictures {
+                data,
+                start: self.latest_pts,
+            });
+            self.synced |= self.sink.is_some();
+        }
+
+        let group = match self.history.back_mut() {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+
+        // Everything added to the group, including any headers, needs writing to the sink
+        let written = if new_group { 0 } else { group.data.len() };
+        group.data.extend_from_slice(buffer.data());
+        self.size += group.data.len() - written;
+
+        if self.synced {
+            if let Some(sink) = self.sink.as_mut() {
+                sink.write_all(&group.data[written..])?;
+            }
+        }
+
+        self.discard_old();
+        Ok(())
+    }
+
+    /// Writes the history to `sink` and starts writing every new buffer to it.
+    ///
+    /// If the recorder was already triggered, the previous sink is flushed and returned.
+    pub fn trigger(&mut self, mut sink: W) -> Result<Option<W>, CameraError> {
+        let previous = self.stop()?;
+
+        for group in self.history.iter() {
+            sink.write_all(&group.data)?;
+        }
+        self.synced = !self.history.is_empty();
+        self.sink = Some(sink);
+
+        Ok(previous)
+    }
+
+    /// Stops writing to the sink and returns it after flushing it.
+    ///
+    /// The recorder carries on keeping the history so it can be triggered again.
+    pub fn stop(&mut self) -> Result<Option<W>, CameraError> {
+        self.synced = false;
+        match self.sink.take() {
+            Some(mut sink) => {
+                sink.flush()?;
+                Ok(Some(sink))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the recorder is writing to a sink.
+    pub fn is_recording(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    /// Number of bytes of video being kept in memory.
+    pub fn buffered_bytes(&self) -> usize {
+        self.size
+    }
+
+    fn discard_old(&mut self) {
+        while self.history.len() > 1 && self.over_limit() {
+            let group = self.history.pop_front().unwrap();
+            self.size -= group.data.len();
+        }
+    }
+
+    /// Whether the oldest group of pictures can be discarded.
+    fn over_limit(&self) -> bool {
+        match self.limit {
+            RecorderLimit::Bytes(max) => self.size > max,
+            // Only discard it if the remaining groups still cover the duration
+            RecorderLimit::Duration(duration) => match (self.history[1].start, self.latest_pts) {
+                (Some(start), Some(latest)) => latest - start >= duration.as_micros() as i64,
+                _ => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+use mmal_sys as ffi;
+
+#[cfg(test)]
+const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x64];
+#[cfg(test)]
+const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xee];
+
+/// Synthetic H.264 where every `gop` frames is a keyframe, 30 frames per second.
+///
+/// Each slice contains its frame number so the output can be checked.
+#[cfg(test)]
+fn synthetic_stream(frames: u32, gop: u32) -> Vec<EncodedBuffer> {
+    let mut buffers = Vec::new();
+    for i in 0..frames {
+        let pts = Some(i as i64 * 33_333);
+        let keyframe = i % gop == 0;
+        if keyframe {
+            buffers.push(EncodedBuffer::new(
+                SPS.to_vec(),
+                ffi::MMAL_BUFFER_HEADER_FLAG_CONFIG,
+                None,
+            ));
+            buffers.push(EncodedBuffer::new(
+                PPS.to_vec(),
+                ffi::MMAL_BUFFER_HEADER_FLAG_CONFIG,
+                None,
+            ));
+        }
+
+        let (nal_type, flags) = if keyframe {
+            (0x65, ffi::MMAL_BUFFER_HEADER_FLAG_KEYFRAME)
+        } else {
+            (0x41, 0)
+        };
+        // The high bit avoids accidental start codes
+        let data = vec![0, 0, 0, 1, nal_type, 0x80 | (i >> 8) as u8, i as u8];
+        buffers.push(EncodedBuffer::new(
+            data,
+            flags | ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END,
+            pts,
+        ));
+    }
+    buffers
+}
+
+/// Returns the NAL unit types and the frame numbers of the slices.
+#[cfg(test)]
+fn parse_nals(data: &[u8]) -> Vec<(u8, Option<u32>)> {
+    let starts: Vec<usize> = (0..data.len().saturating_sub(3))
+        .filter(|&i| data[i..i + 4] == [0, 0, 0, 1])
+        .collect();
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).copied().unwrap_or(data.len());
+            let nal = &data[start + 4..end];
+            let frame = if nal.len() == 3 {
+                Some(((nal[1] & 0x7f) as u32) << 8 | nal[2] as u32)
+            } else {
+                None
+            };
+            (nal[0], frame)
+        })
+        .collect()
+}
+
+#[test]
+fn test_recorder_starts_at_keyframe_with_headers() {
+    let mut recorder = CircularRecorder::new(RecorderLimit::Duration(Duration::from_secs(1)));
+
+    // Start part way through a group of pictures
+    for buffer in synthetic_stream(95, 30).iter().skip(20) {
+        recorder.push(buffer).unwrap();
+    }
+    let out = recorder.trigger(Vec::new()).unwrap();
+    assert!(out.is_none());
+    let data = recorder.stop().unwrap().unwrap();
+
+    let nals = parse_nals(&data);
+    assert_eq!(nals[0], (0x67, None));
+    assert_eq!(nals[1], (0x68, None));
+    assert_eq!(nals[2], (0x65, Some(60)));
+    assert_eq!(nals.last(), Some(&(0x41, Some(94))));
+}
+
+#[test]
+fn test_recorder_discards_by_duration() {
+    let mut recorder = CircularRecorder::new(RecorderLimit::Duration(Duration::from_secs(1)));
+    for buffer in synthetic_stream(300, 10).iter() {
+        recorder.push(buffer).unwrap();
+    }
+    recorder.trigger(Vec::new()).unwrap();
+    let data = recorder.stop().unwrap().unwrap();
+
+    // The frame 1s before the last one is 269, so history starts at the keyframe before it
+    let nals = parse_nals(&data);
+    assert_eq!(nals[0].0, 0x67);
+    assert_eq!(nals[2], (0x65, Some(260)));
+    assert_eq!(nals.last(), Some(&(0x41, Some(299))));
+}
+
+#[test]
+fn test_recorder_discards_by_bytes() {
+    let mut recorder = CircularRecorder::new(RecorderLimit::Bytes(200));
+    for buffer in synthetic_stream(300, 10).iter() {
+        recorder.push(buffer).unwrap();
+        assert!(recorder.buffered_bytes() <= 200);
+    }
+    recorder.trigger(Vec::new()).unwrap();
+    let data = recorder.stop().unwrap().unwrap();
+    assert_eq!(data.len(), recorder.buffered_bytes());
+
+    let nals = parse_nals(&data);
+    assert_eq!(nals[0].0, 0x67);
+    assert_eq!(nals[2].0, 0x65);
+    assert_eq!(nals.last(), Some(&(0x41, Some(299))));
+}
+
+#[test]
+fn test_recorder_keeps_large_group() {
+    let mut recorder = CircularRecorder::new(RecorderLimit::Bytes(10));
+    for buffer in synthetic_stream(25, 30).iter() {
+        recorder.push(buffer).unwrap();
+    }
+    recorder.trigger(Vec::new()).unwrap();
+    let data = recorder.stop().unwrap().unwrap();
+
+    let nals = parse_nals(&data);
+    assert_eq!(nals[2], (0x65, Some(0)));
+    assert_eq!(nals.len(), 27);
+}
+
+#[test]
+fn test_recorder_live_until_stop() {
+    let stream = synthetic_stream(100, 10);
+    let mut recorder = CircularRecorder::new(RecorderLimit::Duration(Duration::from_secs(1)));
+
+    for buffer in stream.iter().take(50) {
+        recorder.push(buffer).unwrap();
+    }
+    recorder.trigger(Vec::new()).unwrap();
+    assert!(recorder.is_recording());
+    for buffer in stream.iter().skip(50).take(30) {
+        recorder.push(buffer).unwrap();
+    }
+    let data = recorder.stop().unwrap().unwrap();
+    assert!(!recorder.is_recording());
+    for buffer in stream.iter().skip(80) {
+        recorder.push(buffer).unwrap();
+    }
+
+    // Every keyframe, including those written live, has its headers
+    let nals = parse_nals(&data);
+    let keyframes = nals
+        .iter()
+        .filter(|(nal_type, _)| *nal_type == 0x65)
+        .count();
+    let headers = nals
+        .iter()
+        .filter(|(nal_type, _)| *nal_type == 0x67)
+        .count();
+    assert_eq!(keyframes, headers);
+
+    let frames: Vec<u32> = nals.into_iter().filter_map(|(_, frame)| frame).collect();
+    let first = frames[0];
+    assert_eq!(first % 10, 0);
+    assert_eq!(
+        frames,
+        (first..frames.len() as u32 + first).collect::<Vec<_>>()
+    );
+    assert!(first <= 10);
+}
+
+#[test]
+fn test_recorder_trigger_before_keyframe() {
+    let stream = synthetic_stream(40, 30);
+    let mut recorder = CircularRecorder::new(RecorderLimit::Duration(Duration::from_secs(1)));
+
+    // Nothing can be decoded yet so recording starts at the next keyframe
+    for buffer in stream.iter().skip(3).take(5) {
+        recorder.push(buffer).unwrap();
+    }
+    recorder.trigger(Vec::new()).unwrap();
+    for buffer in stream.iter().skip(8) {
+        recorder.push(buffer).unwrap();
+    }
+    let data = recorder.stop().unwrap().unwrap();
+
+    let nals = parse_nals(&data);
+    assert_eq!(nals[0].0, 0x67);
+    assert_eq!(nals[2], (0x65, Some(30)));
+}
+
+#[test]
+fn test_recorder_split_keyframe() {
+    let mut recorder = CircularRecorder::new(RecorderLimit::Bytes(0));
+    let keyframe = ffi::MMAL_BUFFER_HEADER_FLAG_KEYFRAME;
+    let frame_end = ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END;
+
+    recorder
+        .push(&EncodedBuffer::new(
+            SPS.to_vec(),
+            ffi::MMAL_BUFFER_HEADER_FLAG_CONFIG,
+            None,
+        ))
+        .unwrap();
+    // A keyframe split over two buffers is a single group
+    recorder
+        .push(&EncodedBuffer::new(vec![1, 2], keyframe, Some(0)))
+        .unwrap();
+    recorder
+        .push(&EncodedBuffer::new(
+            vec![3, 4],
+            keyframe | frame_end,
+            Some(0),
+        ))
+        .unwrap();
+    recorder
+        .push(&EncodedBuffer::new(vec![5], frame_end, Some(1)))
+        .unwrap();
+
+    recorder.trigger(Vec::new()).unwrap();
+    let data = recorder.stop().unwrap().unwrap();
+    let mut expected = SPS.to_vec();
+    expected.extend_from_slice(&[1, 2, 3, 4, 5]);
+    assert_eq!(data, expected);
+}
@@ -2,6 +2,9 @@ use mmal_sys as ffi;
 
 use std::os::raw::c_uint;
 
+use crate::error::{CameraError, ErrorKind, Stage};
+use crate::frame::{Frame, PixelFormat};
+
 pub type ISO = u32;
 
 pub const ISO_AUTO: ISO = 0;
@@ -37,7 +40,7 @@ pub const ISO_3200: ISO = 3200;
 /// };
 /// camera.configure(settings);
 /// ```
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CameraSettings {
     pub encoding: c_uint,
     pub width: u32,  // 0 = max
@@ -46,6 +49,38 @@ pub struct CameraSettings {
     pub zero_copy: bool,
     /// `use_encoder` will go away
     pub use_encoder: bool,
+    /// Fixed white balance gains. `None` turns automatic white balance on.
+    pub awb_gains: Option<AwbGains>,
+    /// Buffers for the port that still images are received from.
+    pub pool: PoolSettings,
+    /// Also scale every still image in hardware, e.g. for a thumbnail. Needs `use_encoder`.
+    ///
+    /// See [`SimpleCamera::take_one_resized`](crate::SimpleCamera::take_one_resized).
+    pub still_resizer: Option<ResizerSettings>,
+}
+
+impl CameraSettings {
+    /// Checks that the still images can be scaled with `still_resizer`, if it is set.
+    pub(crate) fn check_still_resizer(&self) -> Result<(), CameraError> {
+        let resizer = match &self.still_resizer {
+            Some(resizer) => resizer,
+            None => return Ok(()),
+        };
+
+        let problem = if !self.use_encoder {
+            "Still images can only be resized when they are encoded".to_owned()
+        } else if resizer.width == 0 || resizer.height == 0 {
+            format!(
+                "The resizer needs a width and height, got {}x{}",
+                resizer.width, resizer.height
+            )
+        } else if resizer.buffers == 0 {
+            "The resizer needs at least one buffer".to_owned()
+        } else {
+            return Ok(());
+        };
+        Err(ErrorKind::InvalidArgument(Stage::Resizer, problem).into())
+    }
 }
 
 impl Default for CameraSettings {
@@ -57,6 +92,401 @@ impl Default for CameraSettings {
             iso: ISO_AUTO,
             zero_copy: false,
             use_encoder: true,
+            awb_gains: None,
+            pool: PoolSettings::default(),
+            still_resizer: None,
+        }
+    }
+}
+
+/// Buffers for receiving still images.
+///
+/// Each image is split over as many buffers as it needs, and the camera can only fill
+/// buffers which the port has been given. Fewer or smaller buffers save memory, e.g. on a Pi
+/// Zero, while more buffers let captures be queued without waiting for earlier images to be
+/// released.
+///
+/// ```
+/// # use rascam::{CameraSettings, PoolSettings};
+/// let settings = CameraSettings {
+///     pool: PoolSettings {
+///         buffer_num: Some(2),
+///         buffer_size: Some(256 * 1024),
+///         ..PoolSettings::default()
+///     },
+///     ..CameraSettings::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PoolSettings {
+    /// Number of buffers. `None` uses the number the port recommends.
+    ///
+    /// Setting up the camera fails if this is less than the port's minimum.
+    pub buffer_num: Option<u32>,
+    /// Size of each buffer in bytes. `None` uses the size the port recommends.
+    ///
+    /// Setting up the camera fails if this is less than the port's minimum.
+    pub buffer_size: Option<u32>,
+    /// How many buffers of an image can be waiting to be received. `None` allows every
+    /// buffer.
+    ///
+    /// If the receiver falls further behind than this, the rest of the image is discarded and
+    /// its receiver is disconnected, so that the capture fails with
+    /// [`ErrorKind::CaptureFailed`](crate::ErrorKind::CaptureFailed).
+    pub channel_depth: Option<usize>,
+}
+
+/// Red and blue gains for manual white balance, relative to green.
+///
+/// Use [`from_gray_card`] to work out the gains that make a gray card come out neutral:
+///
+/// ```
+/// # use rascam::{AwbGains, Frame, PixelFormat};
+/// // A gray card photographed with gains of 1.5 and 1.5 came out too red
+/// let current = AwbGains { red: 1.5, blue: 1.5 };
+/// let frame = Frame::packed([150, 100, 100].repeat(16), PixelFormat::Rgb24, 4, 4);
+///
+/// let gains = AwbGains::from_gray_card(&frame, current).unwrap();
+/// assert_eq!(gains, AwbGains { red: 1.0, blue: 1.5 });
+/// ```
+///
+/// [`from_gray_card`]: AwbGains::from_gray_card
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AwbGains {
+    pub red: f32,
+    pub blue: f32,
+}
+
+impl AwbGains {
+    /// Works out the gains to use from an image of a gray card which was taken with the
+    /// `current` gains.
+    ///
+    /// The whole frame is used, so it should be filled by the card. Clipped pixels are
+    /// ignored. Returns `None` if the frame isn't RGB or nothing usable was found.
+    pub fn from_gray_card(frame: &Frame, current: AwbGains) -> Option<AwbGains> {
+        AwbGains::from_gray_card_region(frame, current, 0, 0, frame.width(), frame.height())
+    }
+
+    /// Like [`from_gray_card`](AwbGains::from_gray_card) but only uses the part of the frame
+    /// where the card is.
+    ///
+    /// Returns `None` if the region is outside the frame.
+    pub fn from_gray_card_region(
+        frame: &Frame,
+        current: AwbGains,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Option<AwbGains> {
+        let (bytes_per_pixel, red, blue) = match frame.format() {
+            PixelFormat::Rgb24 => (3, 0, 2),
+            PixelFormat::Bgr24 => (3, 2, 0),
+            PixelFormat::Rgba => (4, 0, 2),
+            _ => return None,
+        };
+        if x.checked_add(width)? > frame.width() || y.checked_add(height)? > frame.height() {
+            return None;
+        }
+
+        let mut sums = [0u64; 3];
+        for row in y..y + height {
+            let start = row as usize * frame.stride() + x as usize * bytes_per_pixel;
+            let pixels = &frame.data()[start..start + width as usize * bytes_per_pixel];
+            for pixel in pixels.chunks_exact(bytes_per_pixel) {
+                if pixel[..3].contains(&std::u8::MAX) {
+                    continue;
+                }
+                sums[0] += pixel[red] as u64;
+                sums[1] += pixel[1] as u64;
+                sums[2] += pixel[blue] as u64;
+            }
+        }
+        if sums.contains(&0) {
+            return None;
+        }
+
+        // Scale each gain so that its channel matches green
+        let green = sums[1] as f32;
+        Some(AwbGains {
+            red: current.red * green / sums[0] as f32,
+            blue: current.blue * green / sums[2] as f32,
+        })
+    }
+}
+
+/// What a stream does when frames arrive faster than they are consumed.
+///
+/// The MMAL callback never waits for the consumer. Every policy except `Block` starts
+/// dropping once all but one of the stream's buffers are waiting, so the camera can keep
+/// capturing. Dropped buffers are counted by [`FrameStream::dropped_frames`] and
+/// [`VideoStream::dropped_buffers`].
+///
+/// Dropping part of an encoded stream corrupts the video until the next keyframe.
+///
+/// [`FrameStream::dropped_frames`]: crate::FrameStream::dropped_frames
+/// [`VideoStream::dropped_buffers`]: crate::VideoStream::dropped_buffers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Keep every buffer until it is consumed. Once they are all waiting the camera skips
+    /// frames until one is released. Skipped frames aren't counted.
+    Block,
+    /// Drop the buffer that just arrived, keeping the ones already waiting.
+    DropNewest,
+    /// Drop the oldest waiting buffer so the consumer always gets the latest frames.
+    DropOldest,
+    /// Drop the buffer that just arrived and return an [`ErrorKind::Overflow`] error from
+    /// the next receive.
+    ///
+    /// [`ErrorKind::Overflow`]: crate::ErrorKind::Overflow
+    Error,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure::Block
+    }
+}
+
+/// Settings for streaming raw frames from the camera's video port.
+///
+/// ```
+/// # use rascam::{PixelFormat, StreamSettings};
+/// let settings = StreamSettings {
+///     format: PixelFormat::Rgb24,
+///     frame_rate: 15,
+///     ..StreamSettings::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct StreamSettings {
+    pub format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second
+    pub frame_rate: u32,
+    /// Number of buffers the camera can fill before the oldest frame has been consumed.
+    ///
+    /// This is raised to the minimum the camera requires if it is too low.
+    pub buffers: u32,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        StreamSettings {
+            format: PixelFormat::I420,
+            width: 640,
+            height: 480,
+            frame_rate: 30,
+            buffers: 3,
+        }
+    }
+}
+
+/// Settings for the camera's video port when it feeds a pipeline such as a splitter.
+#[derive(Clone, Debug)]
+pub struct VideoSettings {
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second
+    pub frame_rate: u32,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        VideoSettings {
+            width: 1920,
+            height: 1080,
+            frame_rate: 30,
         }
     }
 }
+
+/// Settings for a video encoder.
+///
+/// ```
+/// # use rascam::EncoderSettings;
+/// let settings = EncoderSettings {
+///     bitrate: 4_000_000,
+///     intra_period: Some(30),
+///     ..EncoderSettings::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct EncoderSettings {
+    /// Output encoding, e.g. `MMAL_ENCODING_H264`
+    pub encoding: c_uint,
+    /// Bits per second
+    pub bitrate: u32,
+    /// Number of frames between keyframes. `None` leaves the encoder's default.
+    pub intra_period: Option<u32>,
+    /// Repeat the SPS and PPS headers before every keyframe.
+    pub inline_headers: bool,
+    /// Produce a [`MotionVectors`](crate::MotionVectors) buffer after every frame.
+    pub inline_motion_vectors: bool,
+    /// Number of buffers the encoder can fill before the oldest has been consumed.
+    pub buffers: u32,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            encoding: ffi::MMAL_ENCODING_H264,
+            bitrate: 17_000_000,
+            intra_period: None,
+            inline_headers: true,
+            inline_motion_vectors: false,
+            buffers: 3,
+        }
+    }
+}
+
+/// The hardware component used to scale frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizerKind {
+    /// `vc.ril.resize`, which is available on all firmware.
+    Resize,
+    /// `vc.ril.isp`, which is faster but is missing from older firmware.
+    Isp,
+}
+
+impl ResizerKind {
+    /// The nul terminated MMAL component name.
+    pub(crate) fn component_name(self) -> &'static [u8] {
+        match self {
+            ResizerKind::Resize => b"vc.ril.resize\0",
+            ResizerKind::Isp => b"vc.ril.isp\0",
+        }
+    }
+}
+
+/// Settings for scaling frames in hardware rather than on the ARM cores.
+///
+/// Video is scaled with [`SeriousCamera::resize`] or a [`SplitterOutput::Resizer`]. Still
+/// images are scaled with [`CameraSettings::still_resizer`], which produces a scaled frame from
+/// the same capture as each still.
+///
+/// [`SeriousCamera::resize`]: crate::SeriousCamera::resize
+///
+/// ```
+/// # use rascam::{PixelFormat, ResizerSettings};
+/// let settings = ResizerSettings {
+///     format: PixelFormat::Rgb24,
+///     width: 320,
+///     height: 240,
+///     ..ResizerSettings::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct ResizerSettings {
+    pub kind: ResizerKind,
+    /// Format of the scaled frames
+    pub format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Number of buffers the resizer can fill before the oldest frame has been consumed.
+    pub buffers: u32,
+}
+
+impl Default for ResizerSettings {
+    fn default() -> Self {
+        ResizerSettings {
+            kind: ResizerKind::Isp,
+            format: PixelFormat::Rgb24,
+            width: 640,
+            height: 480,
+            buffers: 3,
+        }
+    }
+}
+
+/// Where one output of the video splitter is sent.
+///
+/// See [`SeriousCamera::split`](crate::SeriousCamera::split).
+#[derive(Clone, Debug)]
+pub enum SplitterOutput {
+    /// Encode the output, producing a [`VideoStream`](crate::VideoStream).
+    Encoder(EncoderSettings),
+    /// Deliver raw frames, producing a [`FrameStream`](crate::FrameStream).
+    Raw { format: PixelFormat, buffers: u32 },
+    /// Scale the output, producing a [`FrameStream`](crate::FrameStream) of the resized frames.
+    Resizer(ResizerSettings),
+}
+
+#[test]
+fn test_awb_gains_from_gray_card_region() {
+    // A 4x2 BGR frame with padding at the end of each row. The left half is the card and the
+    // right half is a clipped highlight that must be ignored.
+    let stride = 4 * 3 + 4;
+    let mut data = vec![0; stride * 2];
+    for row in 0..2 {
+        for column in 0..4 {
+            let pixel = if column < 2 {
+                [50, 100, 200]
+            } else {
+                [255, 255, 255]
+            };
+            let start = row * stride + column * 3;
+            data[start..start + 3].copy_from_slice(&pixel);
+        }
+    }
+    let frame = Frame::with_layout(data, PixelFormat::Bgr24, 4, 2, stride, 2);
+    let current = AwbGains {
+        red: 2.0,
+        blue: 1.0,
+    };
+
+    let gains = AwbGains::from_gray_card(&frame, current).unwrap();
+    assert_eq!(
+        gains,
+        AwbGains {
+            red: 1.0,
+            blue: 2.0
+        }
+    );
+    assert_eq!(
+        AwbGains::from_gray_card_region(&frame, current, 0, 0, 2, 2),
+        Some(gains)
+    );
+
+    assert_eq!(
+        AwbGains::from_gray_card_region(&frame, current, 2, 0, 2, 2),
+        None
+    );
+    assert_eq!(
+        AwbGains::from_gray_card_region(&frame, current, 3, 0, 2, 2),
+        None
+    );
+    let yuv = Frame::packed(vec![128; 4 * 2 * 3 / 2], PixelFormat::I420, 4, 2);
+    assert_eq!(AwbGains::from_gray_card(&yuv, current), None);
+}
+
+#[test]
+fn test_check_still_resizer() {
+    let mut settings = CameraSettings::default();
+    settings.check_still_resizer().unwrap();
+
+    settings.still_resizer = Some(ResizerSettings::default());
+    settings.check_still_resizer().unwrap();
+
+    let invalid = |settings: &CameraSettings| match settings.check_still_resizer() {
+        Err(err) => match err.kind() {
+            ErrorKind::InvalidArgument(Stage::Resizer, _) => {}
+            kind => panic!("unexpected error {:?}", kind),
+        },
+        Ok(()) => panic!("{:?} should be invalid", settings.still_resizer),
+    };
+
+    settings.use_encoder = false;
+    invalid(&settings);
+    settings.use_encoder = true;
+
+    settings.still_resizer.as_mut().unwrap().height = 0;
+    invalid(&settings);
+
+    settings.still_resizer = Some(ResizerSettings {
+        buffers: 0,
+        ..ResizerSettings::default()
+    });
+    invalid(&settings);
+}
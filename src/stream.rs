@@ -0,0 +1,339 @@
+use mmal_sys as ffi;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::error::CameraError;
+use crate::frame::{Frame, PixelFormat};
+use crate::pipeline::Pipeline;
+use crate::port::{PortBuffer, PortSink};
+use crate::settings::Backpressure;
+use crate::vectors::MotionVectors;
+use crate::{SeriousCamera, MMAL_TIME_UNKNOWN};
+
+/// A continuous stream of raw frames from the camera.
+///
+/// Created by [`SeriousCamera::stream`] or [`SimpleCamera::stream`]. The camera keeps
+/// capturing frames until the stream is dropped. If frames are not consumed as quickly as
+/// they are produced, the camera runs out of buffers and frames are skipped, unless a
+/// different [`Backpressure`] policy is set.
+///
+/// [`SimpleCamera::stream`]: crate::SimpleCamera::stream
+pub struct FrameStream<'a> {
+    // The sink must be dropped before the pipeline which owns its port
+    sink: PortSink,
+    _pipeline: Arc<Pipeline>,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    timeout: Option<Duration>,
+    _camera: PhantomData<&'a mut SeriousCamera>,
+}
+
+impl<'a> FrameStream<'a> {
+    /// Wraps a sink which produces frames with the given format and size.
+    pub(crate) fn new(
+        sink: PortSink,
+        pipeline: Arc<Pipeline>,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> FrameStream<'a> {
+        FrameStream {
+            sink,
+            _pipeline: pipeline,
+            format,
+            width,
+            height,
+            timeout: None,
+            _camera: PhantomData,
+        }
+    }
+
+    /// Sets how long to wait for each buffer of a frame before giving up with an
+    /// [`ErrorKind::Timeout`] error. By default there is no timeout.
+    ///
+    /// [`ErrorKind::Timeout`]: crate::ErrorKind::Timeout
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets what happens when frames arrive faster than they are consumed. Defaults to
+    /// [`Backpressure::Block`].
+    pub fn set_backpressure(&mut self, backpressure: Backpressure) {
+        self.sink.set_backpressure(backpressure);
+    }
+
+    /// Number of frames dropped by the [`Backpressure`] policy since the stream started.
+    pub fn dropped_frames(&self) -> u64 {
+        self.sink.dropped()
+    }
+
+    /// Drops the buffers that are waiting to be received.
+    pub(crate) fn discard_waiting(&mut self) {
+        while let Ok(Some(_)) = self.sink.try_recv() {}
+    }
+
+    /// Waits for the next complete frame.
+    ///
+    /// The frame includes the padding added by the camera. See [`Frame::to_packed`].
+    ///
+    /// A frame which is split over several buffers is skipped if any of its buffers were
+    /// dropped by the [`Backpressure`] policy.
+    pub fn next_frame(&mut self) -> Result<Frame, CameraError> {
+        let expected = Frame::camera_size(self.format, self.width, self.height);
+        let mut data = Vec::with_capacity(expected);
+        // Whether the frame being received has lost some of its buffers
+        let mut skipping = false;
+
+        loop {
+            let buffer = self.sink.recv(self.timeout)?;
+            let frame_end = buffer.flags() & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END > 0;
+
+            // The dropped buffers may have been the middle of a frame, or the start of this one
+            if buffer.after_gap() {
+                debug!("Skipping a frame with dropped buffers");
+                data.clear();
+                skipping = true;
+            }
+
+            if skipping {
+                skipping = !frame_end;
+                continue;
+            }
+
+            data.extend_from_slice(buffer.data());
+
+            if frame_end {
+                if data.len() >= expected {
+                    return Ok(Frame::new(data, self.format, self.width, self.height));
+                }
+
+                debug!(
+                    "Skipping incomplete frame of {} bytes, expected {}",
+                    data.len(),
+                    expected
+                );
+                data.clear();
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for FrameStream<'a> {
+    type Item = Result<Frame, CameraError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_frame())
+    }
+}
+
+/// A buffer of encoded video, such as part of an H.264 stream.
+///
+/// A frame may be split over several buffers, the last of which has
+/// [`is_frame_end`](EncodedBuffer::is_frame_end) set. If the encoder produces motion vectors,
+/// they follow the frame in a buffer of their own. See
+/// [`motion_vectors`](EncodedBuffer::motion_vectors).
+#[derive(Clone, Debug)]
+pub struct EncodedBuffer {
+    data: Vec<u8>,
+    flags: u32,
+    pts: Option<i64>,
+    motion_vectors: Option<MotionVectors>,
+}
+
+impl EncodedBuffer {
+    /// Creates a buffer from data which didn't come from the camera, e.g. for tests.
+    pub fn new(data: Vec<u8>, flags: u32, pts: Option<i64>) -> EncodedBuffer {
+        EncodedBuffer {
+            data,
+            flags,
+            pts,
+            motion_vectors: None,
+        }
+    }
+
+    /// Copies a buffer out of the port, parsing any motion vectors for a frame of the given
+    /// size. Returns `None` for empty buffers.
+    fn from_port_buffer(buffer: &PortBuffer, width: u32, height: u32) -> Option<EncodedBuffer> {
+        if buffer.data().is_empty() {
+            return None;
+        }
+
+        let pts = buffer.pts();
+        let mut encoded = EncodedBuffer::new(
+            buffer.data().to_vec(),
+            buffer.flags(),
+            if pts == MMAL_TIME_UNKNOWN {
+                None
+            } else {
+                Some(pts)
+            },
+        );
+
+        if encoded.is_side_info() {
+            encoded.motion_vectors = MotionVectors::parse(&encoded.data, width, height);
+            if encoded.motion_vectors.is_none() {
+                debug!(
+                    "Unexpected motion vector buffer of {} bytes",
+                    encoded.data.len()
+                );
+            }
+        }
+
+        Some(encoded)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The raw `MMAL_BUFFER_HEADER_FLAG_*` flags.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Presentation timestamp in microseconds, if the encoder provided one.
+    pub fn pts(&self) -> Option<i64> {
+        self.pts
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & ffi::MMAL_BUFFER_HEADER_FLAG_KEYFRAME > 0
+    }
+
+    /// Whether the buffer holds codec configuration (the H.264 SPS and PPS) rather than a frame.
+    pub fn is_config(&self) -> bool {
+        self.flags & ffi::MMAL_BUFFER_HEADER_FLAG_CONFIG > 0
+    }
+
+    pub fn is_frame_end(&self) -> bool {
+        self.flags & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END > 0
+    }
+
+    /// Whether the buffer holds side information, such as motion vectors, rather than part of
+    /// the video stream.
+    ///
+    /// These buffers must not be written out with the video.
+    pub fn is_side_info(&self) -> bool {
+        self.flags & ffi::MMAL_BUFFER_HEADER_FLAG_CODECSIDEINFO > 0
+    }
+
+    /// The motion vectors for the preceding frame, if this is a motion vector buffer.
+    pub fn motion_vectors(&self) -> Option<&MotionVectors> {
+        self.motion_vectors.as_ref()
+    }
+}
+
+/// A continuous stream of encoded video buffers from an encoder.
+///
+/// Created by [`SeriousCamera::split`]. As with [`FrameStream`], buffers are skipped if they
+/// are not consumed quickly enough, unless a different [`Backpressure`] policy is set.
+pub struct VideoStream<'a> {
+    // The sink must be dropped before the pipeline which owns its port
+    sink: PortSink,
+    _pipeline: Arc<Pipeline>,
+    width: u32,
+    height: u32,
+    timeout: Option<Duration>,
+    _camera: PhantomData<&'a mut SeriousCamera>,
+}
+
+impl<'a> VideoStream<'a> {
+    /// Wraps a sink on an encoder which is encoding frames of the given size.
+    pub(crate) fn new(
+        sink: PortSink,
+        pipeline: Arc<Pipeline>,
+        width: u32,
+        height: u32,
+    ) -> VideoStream<'a> {
+        VideoStream {
+            sink,
+            _pipeline: pipeline,
+            width,
+            height,
+            timeout: None,
+            _camera: PhantomData,
+        }
+    }
+
+    /// Sets how long to wait for each buffer before giving up with an [`ErrorKind::Timeout`]
+    /// error. By default there is no timeout.
+    ///
+    /// [`ErrorKind::Timeout`]: crate::ErrorKind::Timeout
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets what happens when buffers arrive faster than they are consumed. Defaults to
+    /// [`Backpressure::Block`].
+    pub fn set_backpressure(&mut self, backpressure: Backpressure) {
+        self.sink.set_backpressure(backpressure);
+    }
+
+    /// Number of buffers dropped by the [`Backpressure`] policy since the stream started.
+    pub fn dropped_buffers(&self) -> u64 {
+        self.sink.dropped()
+    }
+
+    /// Waits for the next buffer from the encoder.
+    pub fn next_buffer(&mut self) -> Result<EncodedBuffer, CameraError> {
+        loop {
+            let buffer = self.sink.recv(self.timeout)?;
+            if let Some(buffer) = EncodedBuffer::from_port_buffer(&buffer, self.width, self.height)
+            {
+                return Ok(buffer);
+            }
+        }
+    }
+
+    /// Returns the next buffer from the encoder if one is ready, without waiting.
+    pub fn try_next_buffer(&mut self) -> Result<Option<EncodedBuffer>, CameraError> {
+        while let Some(buffer) = self.sink.try_recv()? {
+            if let Some(buffer) = EncodedBuffer::from_port_buffer(&buffer, self.width, self.height)
+            {
+                return Ok(Some(buffer));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for VideoStream<'a> {
+    type Item = Result<EncodedBuffer, CameraError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_buffer())
+    }
+}
+
+/// One output of the video splitter.
+///
+/// The variant matches the [`SplitterOutput`](crate::SplitterOutput) it was created from.
+pub enum SplitterStream<'a> {
+    Video(VideoStream<'a>),
+    Frames(FrameStream<'a>),
+}
+
+impl<'a> SplitterStream<'a> {
+    /// Returns the encoded stream, or `None` if this output produces raw frames.
+    pub fn into_video(self) -> Option<VideoStream<'a>> {
+        match self {
+            SplitterStream::Video(stream) => Some(stream),
+            SplitterStream::Frames(_) => None,
+        }
+    }
+
+    /// Returns the raw frame stream, or `None` if this output is encoded.
+    pub fn into_frames(self) -> Option<FrameStream<'a>> {
+        match self {
+            SplitterStream::Video(_) => None,
+            SplitterStream::Frames(stream) => Some(stream),
+        }
+    }
+}
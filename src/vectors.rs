@@ -0,0 +1,117 @@
+/// The motion estimated by the H.264 encoder for one 16x16 macroblock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MotionVector {
+    /// Horizontal motion in pixels
+    pub x: i8,
+    /// Vertical motion in pixels
+    pub y: i8,
+    /// Sum of absolute differences between the macroblock and its reference. Higher values
+    /// mean the match was worse.
+    pub sad: u16,
+}
+
+impl MotionVector {
+    /// Squared length of the vector.
+    pub fn magnitude_squared(&self) -> u32 {
+        let x = self.x as i32;
+        let y = self.y as i32;
+        (x * x + y * y) as u32
+    }
+}
+
+/// A grid of motion vectors, one per macroblock of an encoded frame.
+///
+/// The encoder produces these when [`EncoderSettings::inline_motion_vectors`] is set. They
+/// arrive as separate buffers after the frame they describe. See
+/// [`EncodedBuffer::motion_vectors`].
+///
+/// The grid has one more column than there are macroblocks across the frame. The extra
+/// column doesn't correspond to any part of the image.
+///
+/// [`EncoderSettings::inline_motion_vectors`]: crate::EncoderSettings::inline_motion_vectors
+/// [`EncodedBuffer::motion_vectors`]: crate::EncodedBuffer::motion_vectors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MotionVectors {
+    columns: u32,
+    rows: u32,
+    vectors: Vec<MotionVector>,
+}
+
+impl MotionVectors {
+    /// Parses the side information the encoder produced for a frame of the given size.
+    ///
+    /// Returns `None` if the data is the wrong size.
+    pub fn parse(data: &[u8], width: u32, height: u32) -> Option<MotionVectors> {
+        let (columns, rows) = MotionVectors::grid_size(width, height);
+        if data.len() != (columns * rows) as usize * 4 {
+            return None;
+        }
+
+        let vectors = data
+            .chunks_exact(4)
+            .map(|v| MotionVector {
+                x: v[0] as i8,
+                y: v[1] as i8,
+                sad: u16::from_le_bytes([v[2], v[3]]),
+            })
+            .collect();
+
+        Some(MotionVectors {
+            columns,
+            rows,
+            vectors,
+        })
+    }
+
+    /// Number of columns and rows in the grid for a frame of the given size.
+    pub fn grid_size(width: u32, height: u32) -> (u32, u32) {
+        ((width + 15) / 16 + 1, (height + 15) / 16)
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    /// The vector for the macroblock at the given column and row.
+    pub fn get(&self, column: u32, row: u32) -> Option<&MotionVector> {
+        if column >= self.columns || row >= self.rows {
+            return None;
+        }
+        self.vectors.get((row * self.columns + column) as usize)
+    }
+
+    /// All of the vectors, row by row.
+    pub fn vectors(&self) -> &[MotionVector] {
+        &self.vectors
+    }
+}
+
+#[test]
+fn test_motion_vectors_parse() {
+    // 40x20 is 3x2 macroblocks, plus the extra column
+    assert_eq!(MotionVectors::grid_size(40, 20), (4, 2));
+
+    let mut data = vec![0; 4 * 2 * 4];
+    data[4 * 5..4 * 6].copy_from_slice(&[0xfe, 3, 0x34, 0x12]);
+
+    let vectors = MotionVectors::parse(&data, 40, 20).unwrap();
+    assert_eq!(vectors.columns(), 4);
+    assert_eq!(vectors.rows(), 2);
+    assert_eq!(
+        vectors.get(1, 1),
+        Some(&MotionVector {
+            x: -2,
+            y: 3,
+            sad: 0x1234
+        })
+    );
+    assert_eq!(vectors.get(1, 1).unwrap().magnitude_squared(), 13);
+    assert_eq!(vectors.get(0, 0), Some(&MotionVector::default()));
+    assert_eq!(vectors.get(4, 0), None);
+
+    assert_eq!(MotionVectors::parse(&data[4..], 40, 20), None);
+}